@@ -0,0 +1,124 @@
+use std::{process::ExitStatus, time::Duration};
+
+use tokio::{
+    process::Child,
+    sync::{mpsc, watch},
+};
+
+use super::kill_pid;
+
+type Exit = Option<Result<ExitStatus, String>>;
+
+// a child owned by its own waiter task; we only get to signal it and watch it exit
+#[derive(Debug, Clone)]
+pub struct RunningChild {
+    pub pid: u32,
+    signals: mpsc::UnboundedSender<libc::c_int>,
+    exit: watch::Receiver<Exit>,
+}
+
+impl RunningChild {
+    // the returned future must be polled for the child to be reaped
+    pub(super) fn watch(
+        mut child: Child,
+    ) -> anyhow::Result<(Self, impl std::future::Future<Output = anyhow::Result<()>>)> {
+        let pid = child
+            .id()
+            .ok_or_else(|| anyhow::anyhow!("child exited before we got its pid"))?;
+
+        let (signals, mut signal_rx) = mpsc::unbounded_channel();
+        let (exit_tx, exit) = watch::channel(None);
+
+        let waiter = async move {
+            let status = loop {
+                tokio::select! {
+                    // a reaped pid may be reused, so check for exit before signalling
+                    biased;
+                    status = child.wait() => break status,
+                    Some(signal) = signal_rx.recv() => {
+                        if let Err(e) = kill_pid(pid, signal) {
+                            println!("failed to signal pid={}: {}", pid, e);
+                        }
+                    }
+                }
+            };
+
+            exit_tx.send_replace(Some(status.map_err(|e| e.to_string())));
+
+            Ok(())
+        };
+
+        Ok((Self { pid, signals, exit }, waiter))
+    }
+
+    pub fn signal(&self, signal: libc::c_int) {
+        // the waiter is gone only once the child is
+        let _ = self.signals.send(signal);
+    }
+
+    pub fn exit_status(&self) -> Option<ExitStatus> {
+        self.exit
+            .borrow()
+            .as_ref()
+            .and_then(|exit| exit.as_ref().ok().copied())
+    }
+
+    pub fn has_exited(&self) -> bool {
+        self.exit.borrow().is_some()
+    }
+
+    pub async fn wait(&self) -> anyhow::Result<ExitStatus> {
+        let mut exit = self.exit.clone();
+
+        let exit = exit
+            .wait_for(Option::is_some)
+            .await
+            .map_err(|_| anyhow::anyhow!("lost track of pid={}", self.pid))?;
+
+        match exit.as_ref().unwrap() {
+            Ok(status) => Ok(*status),
+            Err(e) => Err(anyhow::anyhow!(
+                "failed to wait for pid={}: {}",
+                self.pid,
+                e
+            )),
+        }
+    }
+
+    // like `terminate`, for a child that someone else is waiting on
+    pub async fn terminate(&self, grace: Duration) -> anyhow::Result<ExitStatus> {
+        self.signal(libc::SIGTERM);
+
+        if let Ok(status) = tokio::time::timeout(grace, self.wait()).await {
+            return status;
+        }
+
+        self.signal(libc::SIGKILL);
+        self.wait().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn waits_and_terminates() -> anyhow::Result<()> {
+        let child = tokio::process::Command::new("sleep").arg("10").spawn()?;
+        let (running, waiter) = RunningChild::watch(child)?;
+        let waiter = tokio::spawn(waiter);
+
+        assert!(!running.has_exited());
+
+        let status = running.terminate(Duration::from_secs(5)).await?;
+        assert_eq!(
+            std::os::unix::process::ExitStatusExt::signal(&status),
+            Some(libc::SIGTERM)
+        );
+        assert_eq!(running.clone().wait().await?, status);
+
+        waiter.await??;
+
+        Ok(())
+    }
+}
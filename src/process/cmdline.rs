@@ -0,0 +1,378 @@
+use std::{collections::BTreeMap, future::Future, path::PathBuf, process::Stdio};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::AsyncWriteExt,
+    process::{Child, Command},
+};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StdinSource {
+    #[default]
+    Null,
+    File(PathBuf),
+    Bytes(Vec<u8>),
+    // the child's stdin is left on the `Child` for the caller to write to
+    Pipe,
+}
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+type Resource = libc::__rlimit_resource_t;
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+type Resource = libc::c_int;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rlimits {
+    // address space, bytes
+    pub memory: Option<u64>,
+    // seconds
+    pub cpu: Option<u64>,
+    pub open_files: Option<u64>,
+}
+
+impl Rlimits {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    fn limits(&self) -> [(&'static str, Resource, Option<u64>); 3] {
+        [
+            ("memory", libc::RLIMIT_AS, self.memory),
+            ("cpu", libc::RLIMIT_CPU, self.cpu),
+            ("open files", libc::RLIMIT_NOFILE, self.open_files),
+        ]
+    }
+
+    // the first limit above our own hard one, which takes privileges to set
+    fn raised(&self) -> Option<&'static str> {
+        self.limits()
+            .into_iter()
+            .find_map(|(name, resource, limit)| {
+                let mut current = libc::rlimit {
+                    rlim_cur: 0,
+                    rlim_max: 0,
+                };
+
+                let ok = unsafe { libc::getrlimit(resource, &mut current) } == 0;
+                (ok && limit? as libc::rlim_t > current.rlim_max).then_some(name)
+            })
+    }
+
+    // runs in the forked child, so only async-signal-safe calls in here
+    fn apply(&self) -> std::io::Result<()> {
+        for (_, resource, limit) in self.limits() {
+            let Some(limit) = limit else {
+                continue;
+            };
+
+            let rlimit = libc::rlimit {
+                rlim_cur: limit as libc::rlim_t,
+                rlim_max: limit as libc::rlim_t,
+            };
+
+            if unsafe { libc::setrlimit(resource, &rlimit) } != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn default_path() -> String {
+    ".".to_owned()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Cmdline {
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default = "default_path", alias = "cwd")]
+    pub path: String,
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    #[serde(default)]
+    pub env_clear: bool,
+    #[serde(default)]
+    pub stdin: StdinSource,
+    #[serde(default)]
+    pub rlimits: Rlimits,
+    #[serde(default)]
+    pub nice: Option<i32>,
+    #[serde(default)]
+    pub uid: Option<u32>,
+    #[serde(default)]
+    pub gid: Option<u32>,
+}
+
+impl Cmdline {
+    pub fn new(command: &str, args: &Vec<&str>, path: &str) -> Self {
+        Self::program(command)
+            .args(args.iter().copied())
+            .current_dir(path)
+    }
+
+    pub fn program(command: impl Into<String>) -> Self {
+        Self {
+            command: command.into(),
+            args: vec![],
+            path: default_path(),
+            env: BTreeMap::new(),
+            env_clear: false,
+            stdin: StdinSource::Null,
+            rlimits: Rlimits::default(),
+            nice: None,
+            uid: None,
+            gid: None,
+        }
+    }
+
+    pub fn arg(mut self, arg: impl Into<String>) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    pub fn args(mut self, args: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.args.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn current_dir(mut self, path: impl Into<String>) -> Self {
+        self.path = path.into();
+        self
+    }
+
+    pub fn env(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.env.insert(key.into(), value.into());
+        self
+    }
+
+    pub fn envs(
+        mut self,
+        vars: impl IntoIterator<Item = (impl Into<String>, impl Into<String>)>,
+    ) -> Self {
+        self.env
+            .extend(vars.into_iter().map(|(k, v)| (k.into(), v.into())));
+        self
+    }
+
+    pub fn env_clear(mut self) -> Self {
+        self.env_clear = true;
+        self
+    }
+
+    pub fn stdin(mut self, stdin: StdinSource) -> Self {
+        self.stdin = stdin;
+        self
+    }
+
+    pub fn memory_limit(mut self, bytes: u64) -> Self {
+        self.rlimits.memory = Some(bytes);
+        self
+    }
+
+    pub fn cpu_limit(mut self, seconds: u64) -> Self {
+        self.rlimits.cpu = Some(seconds);
+        self
+    }
+
+    pub fn open_files_limit(mut self, files: u64) -> Self {
+        self.rlimits.open_files = Some(files);
+        self
+    }
+
+    pub fn nice(mut self, nice: i32) -> Self {
+        self.nice = Some(nice);
+        self
+    }
+
+    pub fn uid(mut self, uid: u32) -> Self {
+        self.uid = Some(uid);
+        self
+    }
+
+    pub fn gid(mut self, gid: u32) -> Self {
+        self.gid = Some(gid);
+        self
+    }
+
+    pub fn argv(&self) -> Vec<String> {
+        std::iter::once(self.command.clone())
+            .chain(self.args.iter().cloned())
+            .collect()
+    }
+
+    // stdout/stderr are left to the caller
+    pub fn to_command(&self) -> std::io::Result<Command> {
+        let mut command = Command::new(&self.command);
+
+        command.args(&self.args).current_dir(&self.path);
+
+        if self.env_clear {
+            command.env_clear();
+        }
+        command.envs(&self.env);
+
+        command.stdin(match &self.stdin {
+            StdinSource::Null => Stdio::null(),
+            StdinSource::File(path) => std::fs::File::open(path)?.into(),
+            StdinSource::Bytes(_) | StdinSource::Pipe => Stdio::piped(),
+        });
+
+        // `pre_exec` below runs after the switch, when a raised limit or a negative nice would
+        // fail with EPERM; better to say so here than in the child
+        if self.uid.is_some() || self.gid.is_some() {
+            let privileged = match (self.nice.filter(|&nice| nice < 0), self.rlimits.raised()) {
+                (Some(_), _) => Some("a negative nice"),
+                (None, Some(name)) => Some(name),
+                (None, None) => None,
+            };
+
+            if let Some(what) = privileged {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("{} can't be set after switching uid/gid", what),
+                ));
+            }
+        }
+
+        if let Some(uid) = self.uid {
+            command.uid(uid);
+        }
+        if let Some(gid) = self.gid {
+            command.gid(gid);
+        }
+
+        let rlimits = self.rlimits;
+        let nice = self.nice;
+
+        if !rlimits.is_empty() || nice.is_some() {
+            unsafe {
+                command.pre_exec(move || {
+                    rlimits.apply()?;
+
+                    if let Some(nice) = nice {
+                        if libc::setpriority(libc::PRIO_PROCESS, 0, nice) != 0 {
+                            return Err(std::io::Error::last_os_error());
+                        }
+                    }
+
+                    Ok(())
+                });
+            }
+        }
+
+        Ok(command)
+    }
+
+    // for `StdinSource::Bytes`, takes the child's stdin and returns a future writing the bytes into it
+    pub fn stdin_feeder(
+        &self,
+        child: &mut Child,
+    ) -> Option<impl Future<Output = anyhow::Result<()>> + Send + 'static> {
+        let StdinSource::Bytes(bytes) = &self.stdin else {
+            return None;
+        };
+
+        let bytes = bytes.clone();
+        let mut stdin = child.stdin.take()?;
+
+        Some(async move {
+            stdin.write_all(&bytes).await?;
+            stdin.shutdown().await?;
+
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    #[test]
+    fn round_trips_through_serde() -> anyhow::Result<()> {
+        let cmdline = Cmdline::program("cat")
+            .args(["-n", "-"])
+            .current_dir("/tmp")
+            .env("A", "1")
+            .env_clear()
+            .stdin(StdinSource::Bytes(b"hi".to_vec()))
+            .memory_limit(1 << 30)
+            .nice(5)
+            .uid(1000)
+            .gid(1000);
+
+        let json = serde_json::to_string(&cmdline)?;
+        assert_eq!(serde_json::from_str::<Cmdline>(&json)?, cmdline);
+
+        let minimal: Cmdline = serde_json::from_str(r#"{"command": "true", "cwd": "/"}"#)?;
+        assert_eq!(minimal, Cmdline::program("true").current_dir("/"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn clears_env_before_setting_it() -> anyhow::Result<()> {
+        let output = Cmdline::program("/usr/bin/env")
+            .env_clear()
+            .env("ONLY", "this")
+            .to_command()?
+            .output()
+            .await?;
+
+        assert_eq!(String::from_utf8(output.stdout)?, "ONLY=this\n");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn feeds_stdin_bytes() -> anyhow::Result<()> {
+        let cmdline = Cmdline::program("cat").stdin(StdinSource::Bytes(b"fed\nin".to_vec()));
+
+        let mut child = cmdline.to_command()?.stdout(Stdio::piped()).spawn()?;
+        cmdline.stdin_feeder(&mut child).expect("bytes").await?;
+
+        let mut output = String::new();
+        child
+            .stdout
+            .take()
+            .unwrap()
+            .read_to_string(&mut output)
+            .await?;
+        child.wait().await?;
+        assert_eq!(output, "fed\nin");
+
+        assert!(Cmdline::program("cat").stdin_feeder(&mut child).is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_privileges_after_switching_user() {
+        let e = Cmdline::program("true")
+            .uid(65534)
+            .nice(-5)
+            .to_command()
+            .unwrap_err();
+        assert!(e.to_string().contains("negative nice"), "{}", e);
+
+        let e = Cmdline::program("true")
+            .gid(65534)
+            .open_files_limit(u64::MAX - 1)
+            .to_command()
+            .unwrap_err();
+        assert!(e.to_string().contains("open files"), "{}", e);
+
+        assert!(Cmdline::program("true")
+            .uid(65534)
+            .nice(5)
+            .cpu_limit(10)
+            .to_command()
+            .is_ok());
+    }
+}
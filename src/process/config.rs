@@ -0,0 +1,284 @@
+use std::{collections::HashSet, path::Path, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    Cmdline, HealthCheck, MetricsConfig, PtySize, ReadinessCheck, ReadyCondition, RestartPolicy,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProcessConfig {
+    pub name: String,
+    #[serde(flatten)]
+    pub cmdline: Cmdline,
+    #[serde(default)]
+    pub restart: RestartPolicy,
+    #[serde(default)]
+    pub health_check: Option<HealthCheck>,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+    #[serde(default)]
+    pub ready_when: Option<ReadinessCheck>,
+    #[serde(default)]
+    pub pty: Option<PtySize>,
+}
+
+// dependencies first, otherwise in the given order
+pub fn start_order(processes: &[ProcessConfig]) -> anyhow::Result<Vec<String>> {
+    for process in processes {
+        if let Some(dep) = process
+            .depends_on
+            .iter()
+            .find(|dep| !processes.iter().any(|p| &p.name == *dep))
+        {
+            return Err(anyhow::anyhow!(
+                "{} depends on unknown process {}",
+                process.name,
+                dep
+            ));
+        }
+    }
+
+    let mut order: Vec<String> = vec![];
+    let mut remaining = processes.iter().collect::<Vec<_>>();
+
+    while !remaining.is_empty() {
+        let (startable, blocked): (Vec<_>, Vec<_>) = remaining
+            .into_iter()
+            .partition(|process| process.depends_on.iter().all(|dep| order.contains(dep)));
+
+        if startable.is_empty() {
+            return Err(anyhow::anyhow!(
+                "dependency cycle between {}",
+                blocked
+                    .iter()
+                    .map(|process| process.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ));
+        }
+
+        order.extend(startable.into_iter().map(|process| process.name.clone()));
+        remaining = blocked;
+    }
+
+    Ok(order)
+}
+
+fn default_worker_id() -> String {
+    std::env::var("WORKER_ID").unwrap_or_default()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SupervisorConfig {
+    #[serde(default = "default_worker_id")]
+    pub worker_id: String,
+    #[serde(default)]
+    pub state_dir: Option<PathBuf>,
+    #[serde(default, alias = "process")]
+    pub processes: Vec<ProcessConfig>,
+}
+
+impl SupervisorConfig {
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)?;
+
+        let config = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => Self::from_toml(&contents)?,
+            Some("yaml") | Some("yml") => Self::from_yaml(&contents)?,
+            Some("json") => Self::from_json(&contents)?,
+            _ => return Err(anyhow::anyhow!("unknown config format: {}", path.display())),
+        };
+
+        Ok(config)
+    }
+
+    pub fn from_toml(contents: &str) -> anyhow::Result<Self> {
+        let config: Self = toml::from_str(contents)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_yaml(contents: &str) -> anyhow::Result<Self> {
+        let config: Self = serde_yaml::from_str(contents)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_json(contents: &str) -> anyhow::Result<Self> {
+        let config: Self = serde_json::from_str(contents)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let mut names = HashSet::new();
+
+        for process in &self.processes {
+            if !names.insert(process.name.as_str()) {
+                return Err(anyhow::anyhow!("duplicate process name: {}", process.name));
+            }
+        }
+
+        for process in &self.processes {
+            for dep in &process.depends_on {
+                if dep == &process.name {
+                    return Err(anyhow::anyhow!("{} depends on itself", process.name));
+                }
+
+                if !names.contains(dep.as_str()) {
+                    return Err(anyhow::anyhow!(
+                        "{} depends on unknown process {}",
+                        process.name,
+                        dep
+                    ));
+                }
+            }
+
+            match process.ready_when.as_ref().map(|ready| &ready.condition) {
+                Some(ReadyCondition::Healthy) if process.health_check.is_none() => {
+                    return Err(anyhow::anyhow!(
+                        "{} waits to be healthy but has no health check",
+                        process.name
+                    ));
+                }
+                Some(ReadyCondition::LogLine { pattern }) => {
+                    regex::Regex::new(pattern)?;
+                }
+                _ => {}
+            }
+        }
+
+        start_order(&self.processes)?;
+
+        Ok(())
+    }
+
+    pub fn process(&self, name: &str) -> Option<&ProcessConfig> {
+        self.processes.iter().find(|process| process.name == name)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn toml_and_yaml_agree() -> anyhow::Result<()> {
+        let toml = SupervisorConfig::from_toml(
+            r#"
+            worker_id = "w1"
+
+            [[process]]
+            name = "embeddings"
+            command = "python"
+            args = ["serve.py"]
+            cwd = "/srv/embeddings"
+            env = { PORT = "8000" }
+            health_check = { type = "http", url = "http://localhost:8000/", interval = "2s" }
+
+            [[process]]
+            name = "bot"
+            command = "./bot"
+            restart = "on_failure"
+            depends_on = ["embeddings"]
+            "#,
+        )?;
+
+        let yaml = SupervisorConfig::from_yaml(
+            r#"
+            worker_id: w1
+            processes:
+              - name: embeddings
+                command: python
+                args: [serve.py]
+                cwd: /srv/embeddings
+                env: { PORT: "8000" }
+                health_check:
+                  type: http
+                  url: http://localhost:8000/
+                  interval: 2s
+              - name: bot
+                command: ./bot
+                restart: on_failure
+                depends_on: [embeddings]
+            "#,
+        )?;
+
+        assert_eq!(toml, yaml);
+        assert_eq!(toml.processes[0].cmdline.path, "/srv/embeddings");
+        assert_eq!(toml.processes[1].restart, RestartPolicy::OnFailure);
+        assert_eq!(
+            toml.processes[0].health_check.as_ref().unwrap().interval,
+            std::time::Duration::from_secs(2)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_unknown_dependencies() {
+        let config = SupervisorConfig::from_toml(
+            r#"
+            [[process]]
+            name = "bot"
+            command = "./bot"
+            depends_on = ["nope"]
+            "#,
+        );
+
+        assert!(config.is_err());
+    }
+
+    #[test]
+    fn orders_by_dependencies() -> anyhow::Result<()> {
+        let config = SupervisorConfig::from_toml(
+            r#"
+            [[process]]
+            name = "bot"
+            command = "./bot"
+            depends_on = ["embeddings", "db"]
+
+            [[process]]
+            name = "embeddings"
+            command = "./serve"
+            depends_on = ["db"]
+            ready_when = { type = "log_line", pattern = "listening on", timeout = "30s" }
+
+            [[process]]
+            name = "db"
+            command = "./db"
+            "#,
+        )?;
+
+        assert_eq!(start_order(&config.processes)?, ["db", "embeddings", "bot"]);
+        assert_eq!(
+            config.processes[1].ready_when,
+            Some(
+                ReadinessCheck::log_line("listening on")
+                    .timeout(std::time::Duration::from_secs(30))
+            )
+        );
+
+        let cyclic = SupervisorConfig::from_toml(
+            r#"
+            [[process]]
+            name = "a"
+            command = "./a"
+            depends_on = ["b"]
+
+            [[process]]
+            name = "b"
+            command = "./b"
+            depends_on = ["a"]
+            "#,
+        );
+
+        assert!(cyclic.unwrap_err().to_string().contains("cycle"));
+
+        Ok(())
+    }
+}
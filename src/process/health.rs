@@ -0,0 +1,326 @@
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{net::TcpStream, sync::Mutex};
+
+use super::{Cmdline, Journal, JournalEvent, RunningChild};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum HealthProbe {
+    Tcp {
+        address: String,
+    },
+    // needs the `process_health_http` feature
+    Http {
+        url: String,
+    },
+    Command {
+        #[serde(flatten)]
+        cmdline: Cmdline,
+    },
+    // healthy if the pattern showed up in stdout since the previous check
+    Stdout {
+        pattern: String,
+    },
+}
+
+fn default_interval() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn default_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+
+fn default_failure_threshold() -> u32 {
+    3
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HealthCheck {
+    #[serde(flatten)]
+    pub probe: HealthProbe,
+    #[serde(default = "default_interval", with = "humantime_serde")]
+    pub interval: Duration,
+    #[serde(default = "default_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
+    #[serde(default = "default_failure_threshold")]
+    pub failure_threshold: u32,
+    #[serde(default)]
+    pub restart_on_failure: bool,
+}
+
+impl HealthCheck {
+    pub fn new(probe: HealthProbe) -> Self {
+        Self {
+            probe,
+            interval: default_interval(),
+            timeout: default_timeout(),
+            failure_threshold: default_failure_threshold(),
+            restart_on_failure: false,
+        }
+    }
+
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn failure_threshold(mut self, failure_threshold: u32) -> Self {
+        self.failure_threshold = failure_threshold;
+        self
+    }
+
+    pub fn restart_on_failure(mut self) -> Self {
+        self.restart_on_failure = true;
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthStatus {
+    #[default]
+    Unknown,
+    Healthy,
+    Unhealthy,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HealthState {
+    pub status: HealthStatus,
+    pub consecutive_failures: u32,
+    #[serde(with = "humantime_serde")]
+    pub last_check: Option<SystemTime>,
+    pub last_error: Option<String>,
+}
+
+impl HealthState {
+    fn record(&mut self, result: anyhow::Result<()>, failure_threshold: u32) {
+        self.last_check = Some(SystemTime::now());
+
+        match result {
+            Ok(()) => {
+                self.status = HealthStatus::Healthy;
+                self.consecutive_failures = 0;
+                self.last_error = None;
+            }
+            Err(e) => {
+                self.consecutive_failures += 1;
+                self.last_error = Some(e.to_string());
+
+                if self.consecutive_failures >= failure_threshold {
+                    self.status = HealthStatus::Unhealthy;
+                }
+            }
+        }
+    }
+}
+
+struct Prober {
+    check: HealthCheck,
+    regex: Option<regex::Regex>,
+    stdout: Arc<Mutex<String>>,
+    stdout_seen: usize,
+}
+
+impl Prober {
+    async fn probe(&mut self) -> anyhow::Result<()> {
+        match tokio::time::timeout(self.check.timeout, self.probe_inner()).await {
+            Ok(result) => result,
+            Err(_) => Err(anyhow::anyhow!("timed out after {:?}", self.check.timeout)),
+        }
+    }
+
+    async fn probe_inner(&mut self) -> anyhow::Result<()> {
+        match &self.check.probe {
+            HealthProbe::Tcp { address } => {
+                TcpStream::connect(address).await?;
+            }
+            #[cfg(feature = "process_health_http")]
+            HealthProbe::Http { url } => {
+                reqwest::get(url).await?.error_for_status()?;
+            }
+            #[cfg(not(feature = "process_health_http"))]
+            HealthProbe::Http { .. } => {
+                return Err(anyhow::anyhow!(
+                    "http health checks need the process_health_http feature"
+                ));
+            }
+            HealthProbe::Command { cmdline } => {
+                let status = cmdline
+                    .to_command()?
+                    .stdout(std::process::Stdio::null())
+                    .stderr(std::process::Stdio::null())
+                    .kill_on_drop(true)
+                    .status()
+                    .await?;
+
+                if !status.success() {
+                    return Err(anyhow::anyhow!("health command failed: {}", status));
+                }
+            }
+            HealthProbe::Stdout { pattern } => {
+                let stdout = self.stdout.lock().await;
+                // stdout is reset on restart
+                let new_output = stdout.get(self.stdout_seen..).unwrap_or(&stdout);
+                self.stdout_seen = stdout.len();
+
+                if !self.regex.as_ref().unwrap().is_match(new_output) {
+                    return Err(anyhow::anyhow!("{:?} not seen in stdout", pattern));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub(super) async fn run_health_loop(
+    name: String,
+    child: RunningChild,
+    check: HealthCheck,
+    stdout: Arc<Mutex<String>>,
+    state: Arc<Mutex<HealthState>>,
+    journal: Journal,
+) -> anyhow::Result<()> {
+    let regex = match &check.probe {
+        HealthProbe::Stdout { pattern } => Some(regex::Regex::new(pattern)?),
+        _ => None,
+    };
+
+    let mut prober = Prober {
+        check,
+        regex,
+        stdout,
+        stdout_seen: 0,
+    };
+
+    loop {
+        // done once the child exits; its pid may belong to someone else by then
+        let result = tokio::select! {
+            _ = child.wait() => return Ok(()),
+            result = async {
+                tokio::time::sleep(prober.check.interval).await;
+                prober.probe().await
+            } => result,
+        };
+
+        let mut state = state.lock().await;
+        let was = state.status;
+        state.record(result, prober.check.failure_threshold);
+
+        if state.status != was {
+            println!(
+                "{} health: {:?} -> {:?} {}",
+                name,
+                was,
+                state.status,
+                state.last_error.as_deref().unwrap_or("")
+            );
+
+            journal
+                .record(
+                    &name,
+                    JournalEvent::Health {
+                        from: was,
+                        to: state.status,
+                        error: state.last_error.clone(),
+                    },
+                )
+                .await;
+        }
+
+        if state.status == HealthStatus::Unhealthy && prober.check.restart_on_failure {
+            println!("{} is unhealthy, killing pid={}", name, child.pid);
+
+            // the restart loop picks it up from here
+            child.signal(libc::SIGKILL);
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::process::ExitStatusExt;
+
+    use super::*;
+
+    async fn run(
+        test: &str,
+        child: &str,
+        check: HealthCheck,
+    ) -> anyhow::Result<(RunningChild, Arc<Mutex<HealthState>>)> {
+        let dir = std::env::temp_dir().join(format!("{}-{}", test, std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let child = Cmdline::program("sh")
+            .arg("-c")
+            .arg(child)
+            .to_command()?
+            .spawn()?;
+        let (running, waiter) = RunningChild::watch(child)?;
+        tokio::spawn(waiter);
+
+        let state = Arc::new(Mutex::new(HealthState::default()));
+
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            run_health_loop(
+                "test".to_owned(),
+                running.clone(),
+                check.interval(Duration::from_millis(20)),
+                Arc::default(),
+                state.clone(),
+                Journal::in_state_dir(&dir),
+            ),
+        )
+        .await??;
+
+        std::fs::remove_dir_all(&dir)?;
+
+        Ok((running, state))
+    }
+
+    #[tokio::test]
+    async fn kills_after_failure_threshold() -> anyhow::Result<()> {
+        let check = HealthCheck::new(HealthProbe::Command {
+            cmdline: Cmdline::program("false"),
+        })
+        .failure_threshold(2)
+        .restart_on_failure();
+
+        let (running, state) = run("health-kill-test", "sleep 10", check).await?;
+
+        assert_eq!(running.wait().await?.signal(), Some(libc::SIGKILL));
+        let state = state.lock().await;
+        assert_eq!(state.status, HealthStatus::Unhealthy);
+        assert_eq!(state.consecutive_failures, 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stops_when_the_child_exits() -> anyhow::Result<()> {
+        let check = HealthCheck::new(HealthProbe::Command {
+            cmdline: Cmdline::program("true"),
+        })
+        .restart_on_failure();
+
+        let (running, state) = run("health-exit-test", "sleep 0.2", check).await?;
+
+        assert!(running.exit_status().is_some_and(|status| status.success()));
+        assert_eq!(state.lock().await.status, HealthStatus::Healthy);
+
+        Ok(())
+    }
+}
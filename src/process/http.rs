@@ -0,0 +1,298 @@
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+
+use axum::{
+    extract::{Path, Query, Request, State},
+    http::{header, StatusCode},
+    middleware::{self, Next},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    routing::{get, post},
+    Json, Router,
+};
+use futures::{stream, Stream, StreamExt};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use subtle::ConstantTimeEq;
+use tokio::sync::broadcast::error::RecvError;
+
+use super::{JournalQuery, LogLine, LogStream, ProcessRunner, Supervisor};
+
+#[derive(Clone)]
+struct Api {
+    supervisor: Arc<Supervisor>,
+    token: Option<Arc<str>>,
+}
+
+impl Api {
+    async fn runner(&self, name: &str) -> Result<Arc<ProcessRunner>, ApiError> {
+        self.supervisor
+            .runner(name)
+            .await
+            .ok_or_else(|| ApiError(StatusCode::NOT_FOUND, format!("no such process: {}", name)))
+    }
+}
+
+pub struct ApiError(StatusCode, String);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(json!({ "error": self.1 }))).into_response()
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        ApiError(StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+    }
+}
+
+pub fn router(supervisor: Arc<Supervisor>, token: Option<String>) -> Router {
+    let api = Api {
+        supervisor,
+        token: token.map(Into::into),
+    };
+
+    Router::new()
+        .route("/processes", get(list))
+        .route("/processes/{name}", get(status))
+        .route("/processes/{name}/start", post(start))
+        .route("/processes/{name}/stop", post(stop))
+        .route("/processes/{name}/restart", post(restart))
+        .route("/processes/{name}/logs", get(logs))
+        .route("/processes/{name}/journal", get(process_journal))
+        .route("/journal", get(journal))
+        .route_layer(middleware::from_fn_with_state(api.clone(), authorize))
+        .with_state(api)
+}
+
+pub async fn serve(
+    supervisor: Arc<Supervisor>,
+    addr: SocketAddr,
+    token: Option<String>,
+) -> anyhow::Result<()> {
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+
+    println!("control api listening on {}", addr);
+
+    axum::serve(listener, router(supervisor, token)).await?;
+
+    Ok(())
+}
+
+async fn authorize(State(api): State<Api>, req: Request, next: Next) -> Result<Response, ApiError> {
+    if let Some(token) = &api.token {
+        // constant time, so how long it takes doesn't tell how much of the token was right
+        let authorized = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|bearer| bool::from(bearer.as_bytes().ct_eq(token.as_bytes())));
+
+        if !authorized {
+            return Err(ApiError(
+                StatusCode::UNAUTHORIZED,
+                "unauthorized".to_owned(),
+            ));
+        }
+    }
+
+    Ok(next.run(req).await)
+}
+
+async fn list(State(api): State<Api>) -> Result<Json<Value>, ApiError> {
+    let mut list = vec![];
+
+    for runner in api.supervisor.runners().await {
+        let status = runner.get_status().await?;
+
+        list.push(json!({
+            "name": status["name"],
+            "running": status["running"],
+            "pid": status["pid"],
+            "health": status["health"]["status"],
+        }));
+    }
+
+    Ok(Json(json!(list)))
+}
+
+async fn status(State(api): State<Api>, Path(name): Path<String>) -> Result<Json<Value>, ApiError> {
+    Ok(Json(api.runner(&name).await?.get_status().await?))
+}
+
+async fn start(State(api): State<Api>, Path(name): Path<String>) -> Result<Json<Value>, ApiError> {
+    api.runner(&name).await?;
+    api.supervisor.start(&name).await?;
+
+    Ok(Json(json!({ "ok": true })))
+}
+
+async fn stop(State(api): State<Api>, Path(name): Path<String>) -> Result<Json<Value>, ApiError> {
+    api.runner(&name).await?;
+    api.supervisor.stop(&name).await?;
+
+    Ok(Json(json!({ "ok": true })))
+}
+
+async fn restart(
+    State(api): State<Api>,
+    Path(name): Path<String>,
+) -> Result<Json<Value>, ApiError> {
+    api.runner(&name).await?;
+    api.supervisor.restart(&name).await?;
+
+    Ok(Json(json!({ "ok": true })))
+}
+
+async fn journal(
+    State(api): State<Api>,
+    Query(query): Query<JournalQuery>,
+) -> Result<Json<Value>, ApiError> {
+    Ok(Json(json!(api.supervisor.query_journal(&query).await?)))
+}
+
+async fn process_journal(
+    State(api): State<Api>,
+    Path(name): Path<String>,
+    Query(query): Query<JournalQuery>,
+) -> Result<Json<Value>, ApiError> {
+    let query = JournalQuery {
+        process: Some(name),
+        ..query
+    };
+
+    Ok(Json(json!(api.supervisor.query_journal(&query).await?)))
+}
+
+#[derive(Debug, Deserialize)]
+struct LogsQuery {
+    #[serde(default = "default_tail")]
+    tail: usize,
+    stream: Option<LogStream>,
+}
+
+fn default_tail() -> usize {
+    100
+}
+
+fn log_event(line: LogLine) -> Event {
+    Event::default()
+        .event(match line.stream {
+            LogStream::Stdout => "stdout",
+            LogStream::Stderr => "stderr",
+        })
+        .data(line.line)
+}
+
+async fn logs(
+    State(api): State<Api>,
+    Path(name): Path<String>,
+    Query(query): Query<LogsQuery>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, ApiError> {
+    let runner = api.runner(&name).await?;
+    let wanted = move |stream: LogStream| query.stream.is_none_or(|wanted| wanted == stream);
+
+    let (backlog, receiver) = runner.follow_logs(query.tail, wanted).await;
+    let backlog = backlog.into_iter().map(|line| Ok(log_event(line)));
+
+    let live = stream::unfold(receiver, move |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(line) if wanted(line.stream) => return Some((Ok(log_event(line)), receiver)),
+                Ok(_) => continue,
+                Err(RecvError::Lagged(skipped)) => {
+                    let event = Event::default().event("lagged").data(skipped.to_string());
+                    return Some((Ok(event), receiver));
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+
+    Ok(Sse::new(stream::iter(backlog).chain(live)).keep_alive(KeepAlive::default()))
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use axum::body::Body;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::process::Cmdline;
+
+    async fn get(router: &Router, uri: &str, token: Option<&str>) -> anyhow::Result<Response> {
+        let mut req = axum::http::Request::builder().uri(uri);
+        if let Some(token) = token {
+            req = req.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+
+        Ok(router.clone().oneshot(req.body(Body::empty())?).await?)
+    }
+
+    #[tokio::test]
+    async fn serves_processes() -> anyhow::Result<()> {
+        let state_dir = std::env::temp_dir().join(format!("http-test-{}", std::process::id()));
+        let supervisor = Arc::new(Supervisor::new("test".into(), &state_dir));
+        supervisor
+            .adopt(ProcessRunner::with_state_dir(
+                "test".into(),
+                "chatty".into(),
+                Cmdline::program("sh").args([
+                    "-c",
+                    "echo a; sleep 0.2; echo b >&2; sleep 0.2; echo c; exec sleep 1000",
+                ]),
+                &state_dir,
+            )?)
+            .await?;
+        supervisor.start("chatty").await?;
+
+        let router = router(supervisor.clone(), Some("secret".into()));
+
+        let status = |response: Response| response.status();
+        assert_eq!(
+            status(get(&router, "/processes", None).await?),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status(get(&router, "/processes", Some("secreT")).await?),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status(get(&router, "/processes", Some("secret")).await?),
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(get(&router, "/processes/nope", Some("secret")).await?),
+            StatusCode::NOT_FOUND
+        );
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let response = get(&router, "/processes/chatty/logs", Some("secret")).await?;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // the stream stays open for live lines, so only up to the backlog
+        let mut body = response.into_body().into_data_stream();
+        let mut events = String::new();
+        while !events.contains("data: c") {
+            let chunk = tokio::time::timeout(Duration::from_secs(5), body.next())
+                .await?
+                .expect("backlog")?;
+            events.push_str(std::str::from_utf8(&chunk)?);
+        }
+        assert_eq!(
+            events,
+            "event: stdout\ndata: a\n\nevent: stderr\ndata: b\n\nevent: stdout\ndata: c\n\n"
+        );
+
+        supervisor.stop("chatty").await?;
+        let _ = std::fs::remove_dir_all(&state_dir);
+
+        Ok(())
+    }
+}
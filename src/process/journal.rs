@@ -0,0 +1,243 @@
+use std::{
+    fs::OpenOptions,
+    io::{BufRead, BufReader, Write},
+    os::unix::{fs::FileExt, process::ExitStatusExt},
+    path::{Path, PathBuf},
+    process::ExitStatus,
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::HealthStatus;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum JournalEvent {
+    Started {
+        pid: u32,
+    },
+    // on its own
+    Exited {
+        code: Option<i32>,
+        signal: Option<i32>,
+    },
+    // by us
+    Stopped {
+        code: Option<i32>,
+        signal: Option<i32>,
+    },
+    Restarting {
+        attempt: u64,
+    },
+    Health {
+        from: HealthStatus,
+        to: HealthStatus,
+        error: Option<String>,
+    },
+    // start/stop/restart asked for by an operator
+    Command {
+        command: String,
+    },
+}
+
+impl JournalEvent {
+    pub fn exited(status: ExitStatus) -> Self {
+        JournalEvent::Exited {
+            code: status.code(),
+            signal: status.signal(),
+        }
+    }
+
+    pub fn stopped(status: ExitStatus) -> Self {
+        JournalEvent::Stopped {
+            code: status.code(),
+            signal: status.signal(),
+        }
+    }
+
+    pub fn command(command: &str) -> Self {
+        JournalEvent::Command {
+            command: command.to_owned(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JournalEntry {
+    #[serde(with = "humantime_serde")]
+    pub at: SystemTime,
+    pub process: String,
+    #[serde(flatten)]
+    pub event: JournalEvent,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct JournalQuery {
+    pub process: Option<String>,
+    #[serde(default, with = "humantime_serde")]
+    pub since: Option<SystemTime>,
+    // e.g. "12h", relative to now
+    #[serde(default, with = "humantime_serde")]
+    pub last: Option<Duration>,
+    // the newest `limit` entries
+    pub limit: Option<usize>,
+}
+
+impl JournalQuery {
+    fn matches(&self, entry: &JournalEntry, now: SystemTime) -> bool {
+        self.process
+            .as_ref()
+            .is_none_or(|process| &entry.process == process)
+            && self.since.is_none_or(|since| entry.at >= since)
+            && self.last.is_none_or(|last| entry.at + last >= now)
+    }
+}
+
+// one JSONL file per state dir, shared by all its processes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Journal {
+    pub path: PathBuf,
+}
+
+impl Journal {
+    pub fn in_state_dir(state_dir: &Path) -> Self {
+        Self {
+            path: state_dir.join("journal.jsonl"),
+        }
+    }
+
+    pub fn append(&self, entry: &JournalEntry) -> anyhow::Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&self.path)?;
+
+        // after a torn write the entry would end up glued to its remains
+        let mut last = *b"\n";
+        let len = file.metadata()?.len();
+        if len > 0 {
+            file.read_exact_at(&mut last, len - 1)?;
+        }
+
+        let mut line = if last[0] == b'\n' {
+            String::new()
+        } else {
+            "\n".to_owned()
+        };
+        line.push_str(&serde_json::to_string(entry)?);
+        line.push('\n');
+
+        // a single O_APPEND write, so concurrent writers don't interleave
+        file.write_all(line.as_bytes())?;
+
+        Ok(())
+    }
+
+    // the journal is best effort: failing to write it shouldn't take a process down
+    pub async fn record(&self, process: &str, event: JournalEvent) {
+        let entry = JournalEntry {
+            at: SystemTime::now(),
+            process: process.to_owned(),
+            event,
+        };
+
+        // plain file IO, so off the runtime's threads
+        let journal = self.clone();
+        let result = tokio::task::spawn_blocking(move || journal.append(&entry)).await;
+
+        if let Err(e) = result
+            .map_err(anyhow::Error::from)
+            .and_then(|result| result)
+        {
+            println!("failed to write journal {}: {}", self.path.display(), e);
+        }
+    }
+
+    pub async fn query(&self, query: &JournalQuery) -> anyhow::Result<Vec<JournalEntry>> {
+        let (journal, query) = (self.clone(), query.clone());
+
+        tokio::task::spawn_blocking(move || journal.read(&query)).await?
+    }
+
+    fn read(&self, query: &JournalQuery) -> anyhow::Result<Vec<JournalEntry>> {
+        let file = match std::fs::File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        };
+
+        let now = SystemTime::now();
+        let mut entries = vec![];
+
+        for line in BufReader::new(file).split(b'\n') {
+            // a torn line after a crash is skipped rather than failing the whole query, even if it
+            // was cut in the middle of a character
+            let Ok(entry) = serde_json::from_slice::<JournalEntry>(&line?) else {
+                continue;
+            };
+
+            if query.matches(&entry, now) {
+                entries.push(entry);
+            }
+        }
+
+        if let Some(limit) = query.limit {
+            entries.drain(..entries.len().saturating_sub(limit));
+        }
+
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn appends_and_queries() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("journal-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+
+        let journal = Journal::in_state_dir(&dir);
+        journal
+            .record("bot", JournalEvent::Started { pid: 1 })
+            .await;
+        journal.record("db", JournalEvent::command("stop")).await;
+        journal
+            .record("bot", JournalEvent::Restarting { attempt: 1 })
+            .await;
+
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&journal.path)?
+            .write_all(b"{\"torn \xe2\x82")?;
+        journal
+            .record("bot", JournalEvent::Started { pid: 2 })
+            .await;
+
+        let bot = journal
+            .query(&JournalQuery {
+                process: Some("bot".to_owned()),
+                ..Default::default()
+            })
+            .await?;
+        assert_eq!(bot.len(), 3);
+        assert_eq!(bot[1].event, JournalEvent::Restarting { attempt: 1 });
+        assert_eq!(bot[2].event, JournalEvent::Started { pid: 2 });
+
+        let last = journal
+            .query(&JournalQuery {
+                limit: Some(1),
+                last: Some(Duration::from_secs(60)),
+                ..Default::default()
+            })
+            .await?;
+        assert_eq!(last, bot[2..]);
+
+        std::fs::remove_dir_all(&dir)?;
+
+        Ok(())
+    }
+}
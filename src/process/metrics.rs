@@ -0,0 +1,195 @@
+use std::{
+    collections::VecDeque,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use super::{pidfile::stat_fields, RunningChild};
+
+fn default_interval() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn default_history() -> usize {
+    60
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MetricsConfig {
+    #[serde(default = "default_interval", with = "humantime_serde")]
+    pub interval: Duration,
+    #[serde(default = "default_history")]
+    pub history: usize,
+    // the child gets killed (and restarted, per the restart policy) once its rss goes over this
+    #[serde(default)]
+    pub max_rss_bytes: Option<u64>,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            interval: default_interval(),
+            history: default_history(),
+            max_rss_bytes: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResourceSample {
+    #[serde(with = "humantime_serde")]
+    pub at: SystemTime,
+    pub pid: u32,
+    #[serde(with = "humantime_serde")]
+    pub cpu_time: Duration,
+    pub rss_bytes: u64,
+    pub threads: u64,
+    pub open_fds: u64,
+    #[serde(with = "humantime_serde")]
+    pub uptime: Duration,
+}
+
+impl ResourceSample {
+    pub fn read(pid: u32) -> anyhow::Result<Self> {
+        let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid))?;
+        let uptime = std::fs::read_to_string("/proc/uptime")?;
+        let open_fds = std::fs::read_dir(format!("/proc/{}/fd", pid))?.count() as u64;
+
+        let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as f64;
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+
+        Self::parse(pid, &stat, &uptime, open_fds, ticks, page_size)
+    }
+
+    fn parse(
+        pid: u32,
+        stat: &str,
+        uptime: &str,
+        open_fds: u64,
+        ticks: f64,
+        page_size: u64,
+    ) -> anyhow::Result<Self> {
+        let fields =
+            stat_fields(stat).ok_or_else(|| anyhow::anyhow!("can't parse /proc/{}/stat", pid))?;
+
+        // proc(5) numbers the fields from 1, and `fields` starts at field 3
+        let field = |n: usize| -> anyhow::Result<u64> {
+            Ok(fields
+                .get(n - 3)
+                .ok_or_else(|| anyhow::anyhow!("/proc/{}/stat is too short", pid))?
+                .parse()?)
+        };
+
+        let system_uptime: f64 = uptime
+            .split_whitespace()
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("can't parse /proc/uptime"))?
+            .parse()?;
+
+        let started_at = field(22)? as f64 / ticks;
+
+        Ok(Self {
+            at: SystemTime::now(),
+            pid,
+            cpu_time: Duration::from_secs_f64((field(14)? + field(15)?) as f64 / ticks),
+            rss_bytes: field(24)? * page_size,
+            threads: field(20)?,
+            open_fds,
+            uptime: Duration::from_secs_f64((system_uptime - started_at).max(0.0)),
+        })
+    }
+}
+
+pub(super) async fn run_sampler(
+    name: String,
+    child: RunningChild,
+    config: MetricsConfig,
+    history: Arc<Mutex<VecDeque<ResourceSample>>>,
+) -> anyhow::Result<()> {
+    let pid = child.pid;
+
+    loop {
+        // once it's exited, the pid may belong to someone else
+        let (false, Ok(sample)) = (child.has_exited(), ResourceSample::read(pid)) else {
+            return Ok(());
+        };
+
+        let rss_bytes = sample.rss_bytes;
+
+        {
+            let mut history = history.lock().await;
+            history.push_back(sample);
+            while history.len() > config.history {
+                history.pop_front();
+            }
+        }
+
+        if let Some(max_rss_bytes) = config.max_rss_bytes {
+            if rss_bytes > max_rss_bytes {
+                println!(
+                    "{} is using {} bytes of memory (max {}), killing pid={}",
+                    name, rss_bytes, max_rss_bytes, pid
+                );
+
+                child.signal(libc::SIGKILL);
+                return Ok(());
+            }
+        }
+
+        tokio::select! {
+            _ = child.wait() => return Ok(()),
+            _ = tokio::time::sleep(config.interval) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::process::ExitStatusExt;
+
+    use super::*;
+
+    #[test]
+    fn parses_proc_stat() -> anyhow::Result<()> {
+        let stat = "4242 (weird) name) S 1 4242 4242 0 -1 4194560 100 0 0 0 150 50 0 0 20 0 3 0 987654 1000 25";
+        let sample = ResourceSample::parse(4242, stat, "10000.54 20000.00\n", 7, 100.0, 4096)?;
+
+        assert_eq!(sample.cpu_time, Duration::from_secs(2));
+        assert_eq!(sample.rss_bytes, 25 * 4096);
+        assert_eq!(sample.threads, 3);
+        assert_eq!(sample.open_fds, 7);
+        assert_eq!(sample.uptime.as_secs(), 124);
+
+        assert!(ResourceSample::parse(4242, "4242 (short) S 1", "1.0", 0, 100.0, 4096).is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn kills_over_the_memory_limit() -> anyhow::Result<()> {
+        let child = tokio::process::Command::new("sleep").arg("10").spawn()?;
+        let (running, waiter) = RunningChild::watch(child)?;
+        tokio::spawn(waiter);
+
+        let history = Arc::new(Mutex::new(VecDeque::new()));
+        let config = MetricsConfig {
+            interval: Duration::from_millis(20),
+            max_rss_bytes: Some(1),
+            ..Default::default()
+        };
+
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            run_sampler("test".to_owned(), running.clone(), config, history.clone()),
+        )
+        .await??;
+
+        assert_eq!(running.wait().await?.signal(), Some(libc::SIGKILL));
+        assert_eq!(history.lock().await.len(), 1);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,803 @@
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+    process::{ExitStatus, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader},
+    process::Child,
+    sync::{broadcast, watch, Mutex},
+};
+
+use tokio::task::JoinSet;
+
+mod child;
+mod cmdline;
+pub mod config;
+pub mod health;
+pub mod journal;
+pub mod metrics;
+pub mod pidfile;
+mod pipeline;
+pub mod pty;
+pub mod readiness;
+mod run;
+pub mod scheduler;
+mod stdin;
+mod supervisor;
+
+pub use child::RunningChild;
+pub use cmdline::{Cmdline, Rlimits, StdinSource};
+pub use config::{ProcessConfig, SupervisorConfig};
+pub use health::{HealthCheck, HealthProbe, HealthState, HealthStatus};
+pub use journal::{Journal, JournalEntry, JournalEvent, JournalQuery};
+pub use metrics::{MetricsConfig, ResourceSample};
+use pidfile::PidFile;
+pub use pipeline::{Pipeline, PipelineError, PipelineOutput, StageResult};
+pub use pty::{Pty, PtySize};
+pub use readiness::{ReadinessCheck, ReadyCondition};
+pub use run::{run, ExitError, RunOptions, RunOutput};
+pub use scheduler::{OverlapPolicy, RunRecord, Schedule, ScheduledJob};
+pub use stdin::StdinQueue;
+pub use supervisor::{ReloadSummary, Supervisor};
+
+#[cfg(feature = "process_http")]
+#[doc(cfg(process_http))]
+pub mod http;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RestartPolicy {
+    #[default]
+    Always,
+    OnFailure,
+    Never,
+}
+
+impl RestartPolicy {
+    pub fn should_restart(&self, exit: Option<ExitStatus>) -> bool {
+        match self {
+            RestartPolicy::Always => true,
+            RestartPolicy::OnFailure => !exit.is_some_and(|exit| exit.success()),
+            RestartPolicy::Never => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogStream {
+    Stdout,
+    Stderr,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogLine {
+    pub stream: LogStream,
+    pub line: String,
+}
+
+fn kill_pid(pid: u32, signal: libc::c_int) -> anyhow::Result<()> {
+    if unsafe { libc::kill(pid as libc::pid_t, signal) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    Ok(())
+}
+
+pub const DEFAULT_KILL_GRACE: Duration = Duration::from_secs(5);
+
+const MIN_RESTART_INTERVAL: Duration = Duration::from_secs(1);
+const RECENT_LINES: usize = 1000;
+
+// SIGTERM, then SIGKILL if the child is still around after `grace`
+async fn terminate(child: &mut Child, grace: Duration) -> std::io::Result<ExitStatus> {
+    let Some(pid) = child.id() else {
+        return child.wait().await;
+    };
+
+    if kill_pid(pid, libc::SIGTERM).is_ok() {
+        if let Ok(status) = tokio::time::timeout(grace, child.wait()).await {
+            return status;
+        }
+    }
+
+    child.kill().await?;
+    child.wait().await
+}
+
+#[derive(Debug)]
+pub struct ProcessRunner {
+    pub worker_id: String,
+    pub should_restart: Mutex<bool>,
+    pub restart_policy: RestartPolicy,
+    pub name: String,
+    pub cmdline: Cmdline,
+    pub state_dir: PathBuf,
+    pub journal: Journal,
+    pub current: Mutex<Option<RunningChild>>,
+    pub last_exit: Mutex<Option<ExitStatus>>,
+    pub kill_grace: Duration,
+    pub health_check: Option<HealthCheck>,
+    pub health: Arc<Mutex<HealthState>>,
+    pub metrics: MetricsConfig,
+    pub resources: Arc<Mutex<VecDeque<ResourceSample>>>,
+    pub readiness: Option<ReadinessCheck>,
+    pub ready: Arc<watch::Sender<bool>>,
+    pub pty: Option<PtySize>,
+    pub terminal: Mutex<Option<Pty>>,
+    pub stdin_queue: Arc<StdinQueue>,
+    pub stdout: Arc<Mutex<String>>,
+    pub stderr: Arc<Mutex<String>>,
+    pub log_lines: broadcast::Sender<LogLine>,
+    // both streams in the order the lines came in
+    pub recent_lines: Arc<Mutex<VecDeque<LogLine>>>,
+    pub tasks: Mutex<JoinSet<anyhow::Result<()>>>,
+    // orphans are reaped on the first start, not when the runner is built
+    pub reaped: AtomicBool,
+    // set by `restart_looping`, so the loop doesn't take the kill for an exit
+    pub restart_requested: AtomicBool,
+}
+
+impl ProcessRunner {
+    pub fn new(worker_id: String, name: String, cmdline: Cmdline) -> anyhow::Result<Self> {
+        Self::with_state_dir(worker_id, name, cmdline, ".")
+    }
+
+    pub fn with_state_dir(
+        worker_id: String,
+        name: String,
+        cmdline: Cmdline,
+        state_dir: impl Into<PathBuf>,
+    ) -> anyhow::Result<Self> {
+        let state_dir = state_dir.into();
+        std::fs::create_dir_all(&state_dir)?;
+
+        let pr = Self {
+            worker_id,
+            should_restart: Mutex::new(true),
+            restart_policy: RestartPolicy::default(),
+            name,
+            cmdline,
+            journal: Journal::in_state_dir(&state_dir),
+            state_dir,
+            current: Mutex::new(None),
+            last_exit: Mutex::new(None),
+            kill_grace: DEFAULT_KILL_GRACE,
+            health_check: None,
+            health: Arc::new(Mutex::new(HealthState::default())),
+            metrics: MetricsConfig::default(),
+            resources: Arc::new(Mutex::new(VecDeque::new())),
+            readiness: None,
+            ready: Arc::new(watch::channel(false).0),
+            pty: None,
+            terminal: Mutex::new(None),
+            stdin_queue: Arc::new(StdinQueue::new(1024)),
+            stdout: Arc::new(Mutex::new(String::new())),
+            stderr: Arc::new(Mutex::new(String::new())),
+            log_lines: broadcast::channel(1024).0,
+            recent_lines: Arc::new(Mutex::new(VecDeque::new())),
+            tasks: Mutex::new(JoinSet::new()),
+            reaped: AtomicBool::new(false),
+            restart_requested: AtomicBool::new(false),
+        };
+
+        Ok(pr)
+    }
+
+    pub fn with_restart_policy(mut self, restart_policy: RestartPolicy) -> Self {
+        self.restart_policy = restart_policy;
+        self
+    }
+
+    pub fn with_kill_grace(mut self, kill_grace: Duration) -> Self {
+        self.kill_grace = kill_grace;
+        self
+    }
+
+    pub fn with_health_check(mut self, health_check: HealthCheck) -> Self {
+        self.health_check = Some(health_check);
+        self
+    }
+
+    pub fn with_metrics(mut self, metrics: MetricsConfig) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    pub fn with_readiness(mut self, readiness: ReadinessCheck) -> Self {
+        self.readiness = Some(readiness);
+        self
+    }
+
+    // stdin, stdout and stderr all go to one terminal; output shows up as stdout, and starting
+    // fails if stdin is also set to come from a file or bytes
+    pub fn with_pty(mut self, size: PtySize) -> Self {
+        self.pty = Some(size);
+        self
+    }
+
+    pub fn is_ready(&self) -> bool {
+        *self.ready.borrow()
+    }
+
+    // without a readiness check a process is ready as soon as it's spawned
+    pub async fn wait_ready(&self) -> anyhow::Result<()> {
+        let timeout = self
+            .readiness
+            .as_ref()
+            .map_or(readiness::default_timeout(), |readiness| readiness.timeout);
+
+        let mut ready = self.ready.subscribe();
+
+        tokio::time::timeout(timeout, ready.wait_for(|ready| *ready))
+            .await
+            .map_err(|_| anyhow::anyhow!("{} not ready after {:?}", self.name, timeout))??;
+
+        Ok(())
+    }
+
+    pub fn pid_file_path(&self) -> PathBuf {
+        Self::pid_file_in(&self.state_dir, &self.name)
+    }
+
+    fn pid_file_in(state_dir: &Path, name: &str) -> PathBuf {
+        state_dir.join(format!("{}.pid", name))
+    }
+
+    pub fn reap_orphans(name: &str, state_dir: &Path) -> anyhow::Result<()> {
+        let pid_path = Self::pid_file_in(state_dir, name);
+
+        let pidfile = match PidFile::read(&pid_path) {
+            Ok(Some(pidfile)) => pidfile,
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                println!(
+                    "can't parse pid-file for {} ({}), deleting without reaping",
+                    name, e
+                );
+
+                std::fs::remove_file(&pid_path)?;
+                return Ok(());
+            }
+        };
+
+        println!("Found pid-file for {}", name);
+
+        // another runner in this process owns it, e.g. one with the same name that got rejected
+        if pidfile::proc_parent(pidfile.pid).is_ok_and(|parent| parent == std::process::id()) {
+            println!("pid-file for {} belongs to our own child, leaving it", name);
+            return Ok(());
+        }
+
+        if !pidfile.matches_running_process() {
+            println!("pid-file for {} is stale, deleting", name);
+
+            std::fs::remove_file(&pid_path)?;
+            return Ok(());
+        }
+
+        println!(
+            "reaping orphan pid={} cmdline: {}",
+            pidfile.pid,
+            pidfile.cmdline.join(" ")
+        );
+
+        kill_pid(pidfile.pid, libc::SIGKILL)?;
+
+        std::fs::remove_file(&pid_path)?;
+
+        Ok(())
+    }
+
+    // follows restarts: returns once nothing is running, or the current child exited on its own
+    pub async fn wait_until_stop(self: Arc<Self>) -> anyhow::Result<()> {
+        loop {
+            let Some(running) = self.current.lock().await.clone() else {
+                return Ok(());
+            };
+
+            // not holding `current` here, so `stop` and `start` don't have to wait for us
+            let res = running.wait().await;
+
+            let mut cur = self.current.lock().await;
+
+            if cur.as_ref().is_some_and(|cur| cur.pid == running.pid) {
+                let res = res?;
+
+                println!("process exited: {:?}", res);
+                let event = match self.restart_requested.load(Ordering::SeqCst) {
+                    true => JournalEvent::stopped(res),
+                    false => JournalEvent::exited(res),
+                };
+                self.journal.record(&self.name, event).await;
+                *self.last_exit.lock().await = Some(res);
+                *cur = None;
+                return Ok(());
+            }
+        }
+    }
+
+    pub async fn start_and_loop(self: Arc<Self>) -> anyhow::Result<()> {
+        for attempt in 0.. {
+            if !*self.should_restart.lock().await {
+                println!("not restarting");
+                return Ok(());
+            }
+
+            if attempt > 0 {
+                self.journal
+                    .record(&self.name, JournalEvent::Restarting { attempt })
+                    .await;
+            }
+
+            let started = tokio::time::Instant::now();
+
+            self.start().await?;
+            self.clone().wait_until_stop().await?;
+
+            if self.restart_requested.swap(false, Ordering::SeqCst) {
+                continue;
+            }
+
+            // don't spin on a process that dies right away
+            tokio::time::sleep_until(started + MIN_RESTART_INTERVAL).await;
+
+            if !self
+                .restart_policy
+                .should_restart(*self.last_exit.lock().await)
+            {
+                println!("not restarting {}: {:?}", self.name, self.restart_policy);
+                return Ok(());
+            }
+        }
+
+        Ok(())
+    }
+
+    // for a runner under `start_and_loop`: stops the child and lets the loop start the next one,
+    // whatever the restart policy says
+    pub async fn restart_looping(&self) -> anyhow::Result<()> {
+        self.restart_requested.store(true, Ordering::SeqCst);
+
+        let running = self.current.lock().await.clone();
+        if let Some(running) = running {
+            running.terminate(self.kill_grace).await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn disable_restart(&self) -> anyhow::Result<()> {
+        *self.should_restart.lock().await = false;
+
+        Ok(())
+    }
+
+    pub async fn enable_restart(&self) -> anyhow::Result<()> {
+        *self.should_restart.lock().await = true;
+
+        Ok(())
+    }
+
+    pub async fn stop(&self) -> anyhow::Result<()> {
+        // taken out first, so `wait_until_stop` doesn't report it as exited on its own
+        let Some(running) = self.current.lock().await.take() else {
+            return Ok(());
+        };
+
+        match PidFile::read(&self.pid_file_path()) {
+            Ok(Some(pidfile)) if pidfile.pid == running.pid => {
+                std::fs::remove_file(self.pid_file_path())?;
+            }
+            _ => println!("pid mismatch, not deleting .pid"),
+        }
+
+        print!("stopping process");
+        let status = running.terminate(self.kill_grace).await?;
+        self.journal
+            .record(&self.name, JournalEvent::stopped(status))
+            .await;
+        *self.last_exit.lock().await = Some(status);
+        self.ready.send_replace(false);
+        *self.terminal.lock().await = None;
+
+        self.drain_tasks().await;
+
+        Ok(())
+    }
+
+    pub async fn drain_tasks(&self) {
+        let mut tasks = self.tasks.lock().await;
+        tasks.abort_all();
+
+        while let Some(_) = tasks.join_next().await {
+            println!("waiting for pipe reading tasks to finish");
+        }
+    }
+
+    pub async fn start(&self) -> anyhow::Result<()> {
+        let Cmdline {
+            command,
+            args,
+            path,
+            ..
+        } = &self.cmdline;
+
+        // the terminal is the child's stdin, so there'd be nowhere for these to go
+        if self.pty.is_some()
+            && matches!(
+                self.cmdline.stdin,
+                StdinSource::File(_) | StdinSource::Bytes(_)
+            )
+        {
+            return Err(anyhow::anyhow!(
+                "{} runs under a pty, its stdin can't come from a file or bytes",
+                self.name
+            ));
+        }
+
+        // before the first spawn, so it can't overwrite the pid-file
+        if !self.reaped.swap(true, Ordering::SeqCst) {
+            if let Err(e) = Self::reap_orphans(&self.name, &self.state_dir) {
+                println!("failed to reap orphan of {}: {}", self.name, e);
+            }
+        }
+
+        self.stop().await?;
+        self.drain_tasks().await;
+
+        *self.stdout.lock().await = String::new();
+        *self.stderr.lock().await = String::new();
+        self.recent_lines.lock().await.clear();
+        *self.last_exit.lock().await = None;
+        *self.health.lock().await = HealthState::default();
+        self.ready.send_replace(false);
+
+        let ready_regex = match self
+            .readiness
+            .as_ref()
+            .map(|readiness| &readiness.condition)
+        {
+            Some(ReadyCondition::LogLine { pattern }) => Some(regex::Regex::new(pattern)?),
+            _ => None,
+        };
+
+        println!(
+            "starting process: {} {} {:?}",
+            command,
+            args.join(" "),
+            path
+        );
+
+        let mut cur = self.current.lock().await;
+
+        let mut command = self.cmdline.to_command()?;
+        command.env("WORKER_ID", &self.worker_id).kill_on_drop(true);
+
+        let terminal = match self.pty {
+            Some(size) => Some(Pty::attach(&mut command, size)?),
+            None => {
+                command.stdout(Stdio::piped()).stderr(Stdio::piped());
+                None
+            }
+        };
+
+        let mut proc = command.spawn()?;
+        // closes our copies of the terminal's child side, so reading it ends with the child
+        drop(command);
+
+        let pid = proc.id().expect("pid?");
+
+        PidFile::for_child(pid, self.cmdline.argv())?.write(&self.pid_file_path())?;
+        self.journal
+            .record(&self.name, JournalEvent::Started { pid })
+            .await;
+
+        let mut tasks = self.tasks.lock().await;
+        match (&self.readiness, ready_regex) {
+            (None, _) => {
+                self.ready.send_replace(true);
+            }
+            (Some(_), Some(regex)) => {
+                // subscribed before the readers start, so the first line can't be missed
+                tasks.spawn(readiness::wait_log_line(
+                    self.name.clone(),
+                    regex,
+                    self.log_lines.subscribe(),
+                    self.ready.clone(),
+                ));
+            }
+            (Some(_), None) => {
+                tasks.spawn(readiness::wait_healthy(
+                    self.health.clone(),
+                    self.ready.clone(),
+                ));
+            }
+        }
+        if let Some(feeder) = self.cmdline.stdin_feeder(&mut proc) {
+            tasks.spawn(feeder);
+        }
+        if let Some(terminal) = &terminal {
+            tasks.spawn(self.stdin_queue.clone().run_writer(terminal.clone()));
+        } else if let Some(stdin) = proc.stdin.take() {
+            // only `StdinSource::Pipe` leaves it for us
+            tasks.spawn(self.stdin_queue.clone().run_writer(stdin));
+        }
+        if let Some(terminal) = &terminal {
+            tasks.spawn(Self::run_pipe_reader(
+                self.name.clone(),
+                terminal.clone(),
+                self.stdout.clone(),
+                LogStream::Stdout,
+                self.log_lines.clone(),
+                self.recent_lines.clone(),
+            ));
+        } else {
+            tasks.spawn(Self::run_pipe_reader(
+                self.name.clone(),
+                proc.stdout.take().unwrap(),
+                self.stdout.clone(),
+                LogStream::Stdout,
+                self.log_lines.clone(),
+                self.recent_lines.clone(),
+            ));
+            tasks.spawn(Self::run_pipe_reader(
+                self.name.clone(),
+                proc.stderr.take().unwrap(),
+                self.stderr.clone(),
+                LogStream::Stderr,
+                self.log_lines.clone(),
+                self.recent_lines.clone(),
+            ));
+        }
+        *self.terminal.lock().await = terminal;
+
+        let (running, waiter) = RunningChild::watch(proc)?;
+        tasks.spawn(waiter);
+        *cur = Some(running.clone());
+
+        if let Some(health_check) = &self.health_check {
+            tasks.spawn(health::run_health_loop(
+                self.name.clone(),
+                running.clone(),
+                health_check.clone(),
+                self.stdout.clone(),
+                self.health.clone(),
+                self.journal.clone(),
+            ));
+        }
+        tasks.spawn(metrics::run_sampler(
+            self.name.clone(),
+            running,
+            self.metrics.clone(),
+            self.resources.clone(),
+        ));
+
+        Ok(())
+    }
+
+    pub async fn run_pipe_reader(
+        process_name: String,
+        pipe: impl AsyncRead + Unpin,
+        data: Arc<Mutex<String>>,
+        stream: LogStream,
+        log_lines: broadcast::Sender<LogLine>,
+        recent_lines: Arc<Mutex<VecDeque<LogLine>>>,
+    ) -> anyhow::Result<()> {
+        let reader = BufReader::new(pipe);
+
+        let mut lines = reader.lines();
+        while let Some(line) = lines.next_line().await? {
+            println!("from {}: {}", process_name, line);
+            {
+                let mut data = data.lock().await;
+                data.push_str(&line);
+                data.push('\n');
+            }
+
+            let mut recent_lines = recent_lines.lock().await;
+            if recent_lines.len() == RECENT_LINES {
+                recent_lines.pop_front();
+            }
+            recent_lines.push_back(LogLine {
+                stream,
+                line: line.clone(),
+            });
+
+            // under the lock, so `follow_logs` sees each line exactly once; nobody listening is fine
+            let _ = log_lines.send(LogLine { stream, line });
+        }
+
+        Ok(())
+    }
+
+    // queued until the child reads it, across restarts if need be
+    pub async fn write_stdin(&self, data: impl Into<Vec<u8>>) -> anyhow::Result<()> {
+        if self.pty.is_none() && self.cmdline.stdin != StdinSource::Pipe {
+            return Err(anyhow::anyhow!("stdin of {} is not piped", self.name));
+        }
+
+        self.stdin_queue.push(data.into()).await
+    }
+
+    pub async fn send_line(&self, line: &str) -> anyhow::Result<()> {
+        self.write_stdin(format!("{}\n", line)).await
+    }
+
+    // straight to the terminal, skipping the queue; for keystrokes that only make sense right now
+    pub async fn write_input(&self, data: &[u8]) -> anyhow::Result<()> {
+        let terminal = self.terminal.lock().await.clone();
+
+        let Some(mut terminal) = terminal else {
+            return Err(anyhow::anyhow!("{} is not running under a pty", self.name));
+        };
+
+        terminal.write_all(data).await?;
+
+        Ok(())
+    }
+
+    pub async fn resize_pty(&self, size: PtySize) -> anyhow::Result<()> {
+        let Some(terminal) = &*self.terminal.lock().await else {
+            return Err(anyhow::anyhow!("{} is not running under a pty", self.name));
+        };
+
+        terminal.resize(size)?;
+
+        Ok(())
+    }
+
+    pub fn subscribe_logs(&self) -> broadcast::Receiver<LogLine> {
+        self.log_lines.subscribe()
+    }
+
+    // the last `lines` wanted lines of both streams in the order they came, and a receiver for
+    // the ones after them
+    pub async fn follow_logs(
+        &self,
+        lines: usize,
+        wanted: impl Fn(LogStream) -> bool,
+    ) -> (Vec<LogLine>, broadcast::Receiver<LogLine>) {
+        let recent_lines = self.recent_lines.lock().await;
+
+        let mut backlog = recent_lines
+            .iter()
+            .rev()
+            .filter(|line| wanted(line.stream))
+            .take(lines)
+            .cloned()
+            .collect::<Vec<_>>();
+        backlog.reverse();
+
+        (backlog, self.log_lines.subscribe())
+    }
+
+    pub async fn tail(&self, stream: LogStream, lines: usize) -> Vec<String> {
+        let data = match stream {
+            LogStream::Stdout => self.stdout.lock().await,
+            LogStream::Stderr => self.stderr.lock().await,
+        };
+
+        let mut tail = data
+            .lines()
+            .rev()
+            .take(lines)
+            .map(ToOwned::to_owned)
+            .collect::<Vec<_>>();
+        tail.reverse();
+
+        tail
+    }
+
+    pub async fn get_status(&self) -> anyhow::Result<serde_json::Value> {
+        let mut status = serde_json::json!({
+            "process_id": self.worker_id,
+            "name": self.name,
+            "running": false,
+            "restart_policy": self.restart_policy,
+            "last_exit": self.last_exit.lock().await.map(|exit| exit.to_string()),
+            "health": *self.health.lock().await,
+            "ready": self.is_ready(),
+            "stdin_queued": self.stdin_queue.len().await,
+            "resources": *self.resources.lock().await,
+            "stdout": *self.stdout.lock().await,
+            "stderr": *self.stderr.lock().await,
+        });
+
+        if let Some(running) = self.current.lock().await.as_ref() {
+            status["running"] = serde_json::json!(!running.has_exited());
+            status["pid"] = serde_json::json!(running.pid);
+        }
+
+        Ok(status)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::process::Command;
+
+    use super::*;
+
+    // gone, or a zombie nobody reaps
+    fn exited(pid: u32) -> bool {
+        std::fs::read_to_string(format!("/proc/{}/stat", pid))
+            .ok()
+            .and_then(|stat| Some(pidfile::stat_fields(&stat)?.first()? == &"Z"))
+            .unwrap_or(true)
+    }
+
+    #[tokio::test]
+    async fn reaps_orphans_on_first_start() -> anyhow::Result<()> {
+        let state_dir = std::env::temp_dir().join(format!("reap-test-{}", std::process::id()));
+        std::fs::create_dir_all(&state_dir)?;
+
+        // not our child: its parent exits right away
+        let output = Command::new("sh")
+            .args(["-c", "sleep 1000 >/dev/null 2>&1 </dev/null & echo $!"])
+            .output()
+            .await?;
+        let pid: u32 = String::from_utf8(output.stdout)?.trim().parse()?;
+        PidFile::for_child(pid, vec!["sleep".into(), "1000".into()])?
+            .write(&ProcessRunner::pid_file_in(&state_dir, "sleeper"))?;
+
+        let runner = Arc::new(ProcessRunner::with_state_dir(
+            "test".into(),
+            "sleeper".into(),
+            Cmdline::program("sleep").arg("1000"),
+            &state_dir,
+        )?);
+        assert!(!exited(pid));
+
+        runner.start().await?;
+
+        let started = tokio::time::Instant::now();
+        while !exited(pid) {
+            assert!(
+                started.elapsed() < Duration::from_secs(5),
+                "orphan survived"
+            );
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        runner.stop().await?;
+        std::fs::remove_dir_all(&state_dir)?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_pty_with_stdin_source() -> anyhow::Result<()> {
+        let state_dir = std::env::temp_dir().join(format!("pty-stdin-test-{}", std::process::id()));
+
+        for stdin in [
+            StdinSource::File("/dev/null".into()),
+            StdinSource::Bytes(b"hi\n".to_vec()),
+        ] {
+            let runner = ProcessRunner::with_state_dir(
+                "test".into(),
+                "cat".into(),
+                Cmdline::program("cat").stdin(stdin),
+                &state_dir,
+            )?
+            .with_pty(PtySize::default());
+
+            let e = runner.start().await.unwrap_err();
+            assert!(e.to_string().contains("runs under a pty"), "{}", e);
+            assert!(runner.current.lock().await.is_none());
+        }
+
+        std::fs::remove_dir_all(&state_dir)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,168 @@
+use std::{io::Read, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PidFile {
+    pub pid: u32,
+    pub start_time: u64,
+    pub cmdline: Vec<String>,
+}
+
+impl PidFile {
+    pub fn for_child(pid: u32, cmdline: Vec<String>) -> anyhow::Result<Self> {
+        Ok(Self {
+            pid,
+            start_time: proc_start_time(pid)?,
+            cmdline,
+        })
+    }
+
+    pub fn read(path: &Path) -> anyhow::Result<Option<Self>> {
+        let Ok(contents) = std::fs::read_to_string(path) else {
+            return Ok(None);
+        };
+
+        Ok(Some(serde_json::from_str(&contents)?))
+    }
+
+    pub fn write(&self, path: &Path) -> anyhow::Result<()> {
+        let tmp = path.with_extension("pid.tmp");
+
+        std::fs::write(&tmp, serde_json::to_string(self)?)?;
+        std::fs::rename(tmp, path)?;
+
+        Ok(())
+    }
+
+    // pid + start time identify the process; the recorded cmdline has to be the live one,
+    // except that the kernel puts the interpreter (and its argument) in front of scripts
+    pub fn matches_running_process(&self) -> bool {
+        let Ok(start_time) = proc_start_time(self.pid) else {
+            return false;
+        };
+
+        if start_time != self.start_time {
+            return false;
+        }
+
+        let Ok(cmdline) = proc_cmdline(self.pid) else {
+            return false;
+        };
+
+        if self.cmdline.is_empty() {
+            return false;
+        }
+
+        let extra = cmdline.len().saturating_sub(self.cmdline.len());
+
+        cmdline == self.cmdline
+            || (1..=2).contains(&extra)
+                && cmdline.ends_with(&self.cmdline)
+                && is_script(&self.cmdline[0])
+    }
+}
+
+fn is_script(program: &str) -> bool {
+    let mut magic = [0; 2];
+
+    std::fs::File::open(program)
+        .and_then(|mut file| file.read_exact(&mut magic))
+        .is_ok()
+        && magic == *b"#!"
+}
+
+pub fn proc_start_time(pid: u32) -> anyhow::Result<u64> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid))?;
+
+    parse_stat_start_time(&stat).ok_or_else(|| anyhow::anyhow!("can't parse /proc/{}/stat", pid))
+}
+
+pub fn proc_cmdline(pid: u32) -> anyhow::Result<Vec<String>> {
+    let cmdline = std::fs::read(format!("/proc/{}/cmdline", pid))?;
+
+    Ok(cmdline
+        .split(|b| *b == 0)
+        .filter(|arg| !arg.is_empty())
+        .map(|arg| String::from_utf8_lossy(arg).into_owned())
+        .collect())
+}
+
+pub fn proc_parent(pid: u32) -> anyhow::Result<u32> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid))?;
+
+    stat_fields(&stat)
+        .and_then(|fields| fields.get(4 - 3)?.parse().ok())
+        .ok_or_else(|| anyhow::anyhow!("can't parse /proc/{}/stat", pid))
+}
+
+// comm (the 2nd field) is in parens and may itself contain spaces and parens,
+// so we count fields from the last ')'; the returned vec starts at field 3
+pub(super) fn stat_fields(stat: &str) -> Option<Vec<&str>> {
+    Some(stat[stat.rfind(')')? + 1..].split_whitespace().collect())
+}
+
+fn parse_stat_start_time(stat: &str) -> Option<u64> {
+    stat_fields(stat)?.get(22 - 3)?.parse().ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_start_time() {
+        let stat = "4242 (weird) name) S 1 4242 4242 0 -1 4194560 100 0 0 0 1 2 0 0 20 0 1 0 987654 1000 100";
+
+        assert_eq!(parse_stat_start_time(stat), Some(987654));
+    }
+
+    #[test]
+    fn recognizes_itself() -> anyhow::Result<()> {
+        let pid = std::process::id();
+        let pidfile = PidFile::for_child(pid, proc_cmdline(pid)?)?;
+
+        assert!(pidfile.matches_running_process());
+        assert!(!PidFile {
+            start_time: pidfile.start_time + 1,
+            ..pidfile
+        }
+        .matches_running_process());
+
+        Ok(())
+    }
+
+    #[test]
+    fn matches_whole_argv() -> anyhow::Result<()> {
+        let script = std::env::temp_dir().join(format!("pidfile-test-{}.sh", std::process::id()));
+        std::fs::write(&script, "#!/bin/sh\nread line\n")?;
+        std::fs::set_permissions(&script, std::os::unix::fs::PermissionsExt::from_mode(0o755))?;
+
+        let script = script.display().to_string();
+        let mut child = std::process::Command::new(&script)
+            .arg("arg")
+            .stdin(std::process::Stdio::piped())
+            .spawn()?;
+        // the kernel has to have run the interpreter by then
+        std::thread::sleep(std::time::Duration::from_millis(100));
+
+        let matches = |cmdline: &[&str]| -> anyhow::Result<bool> {
+            let cmdline = cmdline.iter().map(|arg| arg.to_string()).collect();
+            Ok(PidFile::for_child(child.id(), cmdline)?.matches_running_process())
+        };
+
+        let live = proc_cmdline(child.id())?;
+        assert!(matches(
+            &live.iter().map(String::as_str).collect::<Vec<_>>()
+        )?);
+        assert!(matches(&[&script, "arg"])?);
+        assert!(!matches(&["arg"])?);
+        assert!(!matches(&[&script])?);
+
+        child.kill()?;
+        child.wait()?;
+        std::fs::remove_file(&script)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,360 @@
+use std::{
+    fmt::Display,
+    process::{ExitStatus, Stdio},
+    sync::Arc,
+    time::Duration,
+};
+
+use futures::future::join_all;
+use tokio::{process::ChildStdout, time::Instant};
+
+use super::{
+    run::{piped_stdin, Capture, LineCallback, STDERR_TAIL_LINES},
+    terminate, Cmdline, RunOptions, StdinSource,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pipeline {
+    pub stages: Vec<Cmdline>,
+}
+
+#[derive(Debug, Clone)]
+pub struct StageResult {
+    pub argv: Vec<String>,
+    // `None` if the stage was still running at the timeout and killed by us
+    pub status: Option<ExitStatus>,
+    pub stderr: String,
+    pub stderr_truncated: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct PipelineOutput {
+    pub stdout: String,
+    pub stdout_truncated: bool,
+    pub stages: Vec<StageResult>,
+    pub duration: Duration,
+}
+
+#[derive(Debug)]
+pub enum PipelineError {
+    Spawn {
+        stage: usize,
+        error: std::io::Error,
+    },
+    Io(std::io::Error),
+    TimedOut {
+        after: Duration,
+        stages: Vec<StageResult>,
+    },
+    // like `set -o pipefail`: the rightmost stage that failed
+    Failed {
+        stage: usize,
+        stages: Vec<StageResult>,
+    },
+}
+
+impl Display for PipelineError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PipelineError::Spawn { stage, error } => {
+                write!(f, "failed to spawn stage {}: {}", stage, error)
+            }
+            PipelineError::Io(e) => write!(f, "i/o error: {}", e),
+            PipelineError::TimedOut { after, .. } => {
+                write!(f, "pipeline timed out after {:?}", after)
+            }
+            PipelineError::Failed { stage, stages } => {
+                let result = &stages[*stage];
+
+                write!(
+                    f,
+                    "stage {} ({}) failed: {}",
+                    stage,
+                    result.argv.join(" "),
+                    result
+                        .status
+                        .map(|status| status.to_string())
+                        .unwrap_or_default()
+                )?;
+
+                let mut tail = result
+                    .stderr
+                    .lines()
+                    .rev()
+                    .take(STDERR_TAIL_LINES)
+                    .collect::<Vec<_>>();
+                tail.reverse();
+
+                if !tail.is_empty() {
+                    write!(f, "\n{}", tail.join("\n"))?;
+                }
+
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for PipelineError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PipelineError::Spawn { error, .. } | PipelineError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl Cmdline {
+    pub fn pipe(self, next: Cmdline) -> Pipeline {
+        Pipeline {
+            stages: vec![self, next],
+        }
+    }
+}
+
+impl FromIterator<Cmdline> for Pipeline {
+    fn from_iter<I: IntoIterator<Item = Cmdline>>(iter: I) -> Self {
+        Self {
+            stages: iter.into_iter().collect(),
+        }
+    }
+}
+
+impl Pipeline {
+    pub fn pipe(mut self, next: Cmdline) -> Self {
+        self.stages.push(next);
+        self
+    }
+
+    // `options.stdin` goes to the first stage, `on_stdout_line` sees the last stage's output,
+    // and `on_stderr_line` sees every stage's stderr
+    pub async fn run(&self, mut options: RunOptions) -> Result<PipelineOutput, PipelineError> {
+        if self.stages.is_empty() {
+            return Err(PipelineError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "empty pipeline",
+            )));
+        }
+
+        let started = Instant::now();
+
+        let mut children = vec![];
+        let mut stderrs = vec![];
+        let mut feeder = None;
+        let mut previous_stdout: Option<ChildStdout> = None;
+
+        for (i, stage) in self.stages.iter().enumerate() {
+            let mut stage = stage.clone();
+
+            if i == 0 {
+                if let Some(stdin) = options.stdin.take() {
+                    stage.stdin = StdinSource::Bytes(stdin);
+                }
+            }
+
+            let spawn_error = |error| PipelineError::Spawn { stage: i, error };
+
+            // the later stages read the one before them, whatever they say
+            if i == 0 && stage.stdin == StdinSource::Pipe {
+                return Err(spawn_error(piped_stdin()));
+            }
+
+            let mut command = stage.to_command().map_err(spawn_error)?;
+
+            if let Some(stdout) = previous_stdout.take() {
+                let stdin: Stdio = stdout.try_into().map_err(spawn_error)?;
+                command.stdin(stdin);
+            }
+
+            let mut child = command
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .kill_on_drop(true)
+                .spawn()
+                .map_err(spawn_error)?;
+
+            if i == 0 {
+                feeder = stage.stdin_feeder(&mut child);
+            }
+
+            previous_stdout = child.stdout.take();
+            stderrs.push(child.stderr.take().unwrap());
+            children.push(child);
+        }
+
+        let stdout = previous_stdout.unwrap();
+
+        let mut out = Capture::new(options.capture_limit);
+        let mut errs = self
+            .stages
+            .iter()
+            .map(|_| Capture::new(options.capture_limit))
+            .collect::<Vec<_>>();
+
+        let shared_on_stderr = options
+            .on_stderr_line
+            .take()
+            .map(|f| Arc::new(std::sync::Mutex::new(f)));
+        let mut on_stderr = self
+            .stages
+            .iter()
+            .map(|_| {
+                shared_on_stderr.clone().map(|f| -> LineCallback {
+                    Box::new(move |line: &str| (f.lock().unwrap())(line))
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let result = {
+            let work = async {
+                let (out_result, err_results, statuses, _) =
+                    tokio::join!(
+                        out.read(stdout, options.on_stdout_line.as_mut()),
+                        join_all(errs.iter_mut().zip(stderrs).zip(on_stderr.iter_mut()).map(
+                            |((capture, pipe), on_line)| capture.read(pipe, on_line.as_mut())
+                        )),
+                        join_all(children.iter_mut().map(|child| child.wait())),
+                        async {
+                            if let Some(feeder) = feeder {
+                                let _ = feeder.await;
+                            }
+                        },
+                    );
+
+                out_result?;
+                err_results.into_iter().collect::<std::io::Result<()>>()?;
+                statuses.into_iter().collect::<std::io::Result<Vec<_>>>()
+            };
+
+            match options.timeout {
+                Some(timeout) => tokio::time::timeout(timeout, work).await.ok(),
+                None => Some(work.await),
+            }
+        };
+
+        let timed_out = result.is_none();
+
+        let statuses = match result {
+            Some(statuses) => statuses
+                .map_err(PipelineError::Io)?
+                .into_iter()
+                .map(Some)
+                .collect::<Vec<_>>(),
+            None => {
+                let kill_grace = options.kill_grace;
+
+                // the stages that finished keep their status, the rest get their grace all at once
+                join_all(children.iter_mut().map(|child| async move {
+                    match child.try_wait()? {
+                        Some(status) => Ok(Some(status)),
+                        None => terminate(child, kill_grace).await.map(|_| None),
+                    }
+                }))
+                .await
+                .into_iter()
+                .collect::<std::io::Result<Vec<_>>>()
+                .map_err(PipelineError::Io)?
+            }
+        };
+
+        let stages = self
+            .stages
+            .iter()
+            .zip(errs)
+            .zip(&statuses)
+            .map(|((stage, mut err), status)| {
+                let (stderr, stderr_truncated) = err.take();
+
+                StageResult {
+                    argv: stage.argv(),
+                    status: *status,
+                    stderr,
+                    stderr_truncated,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        if timed_out {
+            return Err(PipelineError::TimedOut {
+                after: started.elapsed(),
+                stages,
+            });
+        }
+
+        if let Some(stage) = stages
+            .iter()
+            .rposition(|stage| !stage.status.is_some_and(|status| status.success()))
+        {
+            return Err(PipelineError::Failed { stage, stages });
+        }
+
+        let (stdout, stdout_truncated) = out.take();
+
+        Ok(PipelineOutput {
+            stdout,
+            stdout_truncated,
+            stages,
+            duration: started.elapsed(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn pipes_and_fails() -> anyhow::Result<()> {
+        let sorted = Cmdline::program("sort")
+            .pipe(Cmdline::program("head").args(["-n", "1"]))
+            .run(RunOptions::default().stdin("b\na\nc\n"))
+            .await?;
+        assert_eq!(sorted.stdout, "a\n");
+
+        let failing = Cmdline::program("sh")
+            .args(["-c", "echo nope >&2; exit 2"])
+            .pipe(Cmdline::program("cat"))
+            .run(RunOptions::default())
+            .await;
+
+        let Err(PipelineError::Failed { stage, stages }) = failing else {
+            panic!("should fail");
+        };
+        assert_eq!(stage, 0);
+        assert_eq!(stages[0].stderr, "nope\n");
+
+        let piped = Cmdline::program("cat")
+            .stdin(StdinSource::Pipe)
+            .pipe(Cmdline::program("sort"))
+            .run(RunOptions::default())
+            .await;
+        assert!(matches!(
+            piped,
+            Err(PipelineError::Spawn { stage: 0, error })
+                if error.kind() == std::io::ErrorKind::InvalidInput
+        ));
+
+        let started = Instant::now();
+        let ignoring_term = || Cmdline::program("sh").args(["-c", "trap '' TERM; exec sleep 5"]);
+        let timed_out = Cmdline::program("sh")
+            .args(["-c", "exit 3"])
+            .pipe(ignoring_term())
+            .pipe(ignoring_term())
+            .run(
+                RunOptions::default()
+                    .timeout(Duration::from_millis(200))
+                    .kill_grace(Duration::from_millis(500)),
+            )
+            .await;
+
+        let Err(PipelineError::TimedOut { stages, .. }) = timed_out else {
+            panic!("should time out");
+        };
+        assert_eq!(stages[0].status.and_then(|status| status.code()), Some(3));
+        assert!(stages[1..].iter().all(|stage| stage.status.is_none()));
+        // one grace for both, not one after the other
+        assert!(started.elapsed() < Duration::from_millis(1100));
+
+        Ok(())
+    }
+}
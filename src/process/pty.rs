@@ -0,0 +1,238 @@
+use std::{
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    pin::Pin,
+    process::Stdio,
+    sync::Arc,
+    task::{ready, Context, Poll},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{unix::AsyncFd, AsyncRead, AsyncWrite, ReadBuf},
+    process::Command,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PtySize {
+    pub rows: u16,
+    pub cols: u16,
+}
+
+impl Default for PtySize {
+    fn default() -> Self {
+        Self { rows: 24, cols: 80 }
+    }
+}
+
+impl PtySize {
+    fn winsize(&self) -> libc::winsize {
+        libc::winsize {
+            ws_row: self.rows,
+            ws_col: self.cols,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        }
+    }
+}
+
+fn check(result: libc::c_int) -> io::Result<libc::c_int> {
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(result)
+}
+
+// the master side; clones share the same terminal
+#[derive(Debug, Clone)]
+pub struct Pty {
+    master: Arc<AsyncFd<OwnedFd>>,
+}
+
+impl Pty {
+    // wires `command`'s stdio to a fresh terminal that becomes its controlling tty
+    pub fn attach(command: &mut Command, size: PtySize) -> io::Result<Self> {
+        let (mut master, mut slave) = (0, 0);
+
+        unsafe {
+            check(libc::openpty(
+                &mut master,
+                &mut slave,
+                std::ptr::null_mut(),
+                std::ptr::null(),
+                &size.winsize(),
+            ))?;
+        }
+
+        let master = unsafe { OwnedFd::from_raw_fd(master) };
+        let slave = unsafe { OwnedFd::from_raw_fd(slave) };
+
+        unsafe {
+            // keep "\n" as is, so captured lines don't end in "\r"
+            let mut termios = std::mem::zeroed::<libc::termios>();
+            check(libc::tcgetattr(slave.as_raw_fd(), &mut termios))?;
+            termios.c_oflag &= !libc::ONLCR;
+            check(libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios))?;
+
+            let flags = check(libc::fcntl(master.as_raw_fd(), libc::F_GETFL))?;
+            check(libc::fcntl(
+                master.as_raw_fd(),
+                libc::F_SETFL,
+                flags | libc::O_NONBLOCK,
+            ))?;
+            for fd in [&master, &slave] {
+                check(libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC))?;
+            }
+        }
+
+        command
+            .stdin(Stdio::from(slave.try_clone()?))
+            .stdout(Stdio::from(slave.try_clone()?))
+            .stderr(Stdio::from(slave));
+
+        unsafe {
+            command.pre_exec(|| {
+                // a new session without a terminal, then stdin becomes its terminal
+                check(libc::setsid())?;
+                check(libc::ioctl(0, libc::TIOCSCTTY, 0))?;
+
+                Ok(())
+            });
+        }
+
+        // SAFETY: `master` is an open fd we own, and it moves into the `AsyncFd`, which closes it
+        // only when it's dropped, so it stays the same fd for as long as it's registered
+        let master = unsafe { AsyncFd::register(master)? };
+
+        Ok(Self {
+            master: Arc::new(master),
+        })
+    }
+
+    pub fn resize(&self, size: PtySize) -> io::Result<()> {
+        unsafe {
+            check(libc::ioctl(
+                self.master.as_raw_fd(),
+                libc::TIOCSWINSZ,
+                &size.winsize(),
+            ))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl AsyncRead for Pty {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            let mut guard = ready!(self.master.poll_read_ready(cx))?;
+
+            let unfilled = buf.initialize_unfilled();
+            let result = guard.try_io(|master| {
+                let read = unsafe {
+                    libc::read(
+                        master.as_raw_fd(),
+                        unfilled.as_mut_ptr() as *mut libc::c_void,
+                        unfilled.len(),
+                    )
+                };
+
+                if read < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+
+                Ok(read as usize)
+            });
+
+            match result {
+                Ok(Ok(read)) => {
+                    buf.advance(read);
+                    return Poll::Ready(Ok(()));
+                }
+                // every process holding the terminal is gone: that's the end of output
+                Ok(Err(e)) if e.raw_os_error() == Some(libc::EIO) => return Poll::Ready(Ok(())),
+                Ok(Err(e)) => return Poll::Ready(Err(e)),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for Pty {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            let mut guard = ready!(self.master.poll_write_ready(cx))?;
+
+            let result = guard.try_io(|master| {
+                let written = unsafe {
+                    libc::write(
+                        master.as_raw_fd(),
+                        data.as_ptr() as *const libc::c_void,
+                        data.len(),
+                    )
+                };
+
+                if written < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+
+                Ok(written as usize)
+            });
+
+            if let Ok(result) = result {
+                return Poll::Ready(result);
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn runs_under_a_terminal() -> anyhow::Result<()> {
+        let mut command = Command::new("sh");
+        command.args(["-c", "read line; stty size; test -t 1 && echo tty $line"]);
+
+        let mut pty = Pty::attach(
+            &mut command,
+            PtySize {
+                rows: 30,
+                cols: 100,
+            },
+        )?;
+        let mut child = command.spawn()?;
+        drop(command);
+
+        pty.write_all(b"hello\n").await?;
+
+        let mut output = String::new();
+        pty.read_to_string(&mut output).await?;
+        child.wait().await?;
+
+        // the terminal echoes input back
+        assert_eq!(output, "hello\n30 100\ntty hello\n");
+
+        Ok(())
+    }
+}
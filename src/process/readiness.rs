@@ -0,0 +1,86 @@
+use std::{sync::Arc, time::Duration};
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, broadcast::error::RecvError, watch, Mutex};
+
+use super::{HealthState, HealthStatus, LogLine};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ReadyCondition {
+    // needs a health check on the same process
+    Healthy,
+    LogLine { pattern: String },
+}
+
+pub(super) fn default_timeout() -> Duration {
+    Duration::from_secs(60)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReadinessCheck {
+    #[serde(flatten)]
+    pub condition: ReadyCondition,
+    #[serde(default = "default_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
+}
+
+impl ReadinessCheck {
+    pub fn new(condition: ReadyCondition) -> Self {
+        Self {
+            condition,
+            timeout: default_timeout(),
+        }
+    }
+
+    pub fn healthy() -> Self {
+        Self::new(ReadyCondition::Healthy)
+    }
+
+    pub fn log_line(pattern: impl Into<String>) -> Self {
+        Self::new(ReadyCondition::LogLine {
+            pattern: pattern.into(),
+        })
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+pub(super) async fn wait_healthy(
+    health: Arc<Mutex<HealthState>>,
+    ready: Arc<watch::Sender<bool>>,
+) -> anyhow::Result<()> {
+    while health.lock().await.status != HealthStatus::Healthy {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+
+    ready.send_replace(true);
+
+    Ok(())
+}
+
+pub(super) async fn wait_log_line(
+    name: String,
+    regex: Regex,
+    mut lines: broadcast::Receiver<LogLine>,
+    ready: Arc<watch::Sender<bool>>,
+) -> anyhow::Result<()> {
+    loop {
+        match lines.recv().await {
+            Ok(line) if regex.is_match(&line.line) => break,
+            Ok(_) => {}
+            Err(RecvError::Lagged(skipped)) => {
+                println!("{} readiness check missed {} log lines", name, skipped);
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        }
+    }
+
+    ready.send_replace(true);
+
+    Ok(())
+}
@@ -0,0 +1,429 @@
+use std::{
+    fmt::Display,
+    process::{ExitStatus, Stdio},
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, BufReader},
+    sync::Notify,
+    time::Instant,
+};
+
+use super::{terminate, Cmdline, StdinSource, DEFAULT_KILL_GRACE};
+
+pub(super) const STDERR_TAIL_LINES: usize = 20;
+
+pub(super) type LineCallback = Box<dyn FnMut(&str) + Send>;
+
+pub struct RunOptions {
+    pub timeout: Option<Duration>,
+    pub kill_grace: Duration,
+    // per stream; past that only the tail is kept
+    pub capture_limit: usize,
+    pub stdin: Option<Vec<u8>>,
+    pub on_stdout_line: Option<LineCallback>,
+    pub on_stderr_line: Option<LineCallback>,
+    // terminated the same way as on timeout once this is notified
+    pub stop: Option<Arc<Notify>>,
+}
+
+impl Default for RunOptions {
+    fn default() -> Self {
+        Self {
+            timeout: None,
+            kill_grace: DEFAULT_KILL_GRACE,
+            capture_limit: 1 << 20,
+            stdin: None,
+            on_stdout_line: None,
+            on_stderr_line: None,
+            stop: None,
+        }
+    }
+}
+
+impl RunOptions {
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn kill_grace(mut self, kill_grace: Duration) -> Self {
+        self.kill_grace = kill_grace;
+        self
+    }
+
+    pub fn capture_limit(mut self, bytes: usize) -> Self {
+        self.capture_limit = bytes;
+        self
+    }
+
+    pub fn stdin(mut self, stdin: impl Into<Vec<u8>>) -> Self {
+        self.stdin = Some(stdin.into());
+        self
+    }
+
+    pub fn on_stdout_line(mut self, f: impl FnMut(&str) + Send + 'static) -> Self {
+        self.on_stdout_line = Some(Box::new(f));
+        self
+    }
+
+    pub fn on_stderr_line(mut self, f: impl FnMut(&str) + Send + 'static) -> Self {
+        self.on_stderr_line = Some(Box::new(f));
+        self
+    }
+
+    pub fn stop_on(mut self, stop: Arc<Notify>) -> Self {
+        self.stop = Some(stop);
+        self
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RunOutput {
+    pub status: ExitStatus,
+    pub stdout: String,
+    pub stderr: String,
+    pub stdout_truncated: bool,
+    pub stderr_truncated: bool,
+    pub duration: Duration,
+}
+
+#[derive(Debug)]
+pub enum ExitError {
+    Spawn(std::io::Error),
+    Io(std::io::Error),
+    TimedOut {
+        after: Duration,
+        stderr_tail: String,
+    },
+    Failed {
+        status: ExitStatus,
+        stderr_tail: String,
+    },
+    Stopped {
+        stderr_tail: String,
+    },
+}
+
+impl Display for ExitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExitError::Spawn(e) => write!(f, "failed to spawn: {}", e),
+            ExitError::Io(e) => write!(f, "i/o error: {}", e),
+            ExitError::TimedOut { after, stderr_tail } => {
+                write!(f, "timed out after {:?}", after)?;
+                write_tail(f, stderr_tail)
+            }
+            ExitError::Failed {
+                status,
+                stderr_tail,
+            } => {
+                write!(f, "{}", status)?;
+                write_tail(f, stderr_tail)
+            }
+            ExitError::Stopped { stderr_tail } => {
+                write!(f, "stopped")?;
+                write_tail(f, stderr_tail)
+            }
+        }
+    }
+}
+
+fn write_tail(f: &mut std::fmt::Formatter<'_>, stderr_tail: &str) -> std::fmt::Result {
+    if stderr_tail.is_empty() {
+        return Ok(());
+    }
+
+    write!(f, "\n{}", stderr_tail)
+}
+
+impl std::error::Error for ExitError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ExitError::Spawn(e) | ExitError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl ExitError {
+    pub fn stderr_tail(&self) -> Option<&str> {
+        match self {
+            ExitError::TimedOut { stderr_tail, .. }
+            | ExitError::Failed { stderr_tail, .. }
+            | ExitError::Stopped { stderr_tail } => Some(stderr_tail),
+            _ => None,
+        }
+    }
+}
+
+pub(super) struct Capture {
+    limit: usize,
+    // up to twice the limit, so it's cut once in a while instead of on every line
+    data: String,
+    truncated: bool,
+}
+
+impl Capture {
+    pub(super) fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            data: String::new(),
+            truncated: false,
+        }
+    }
+
+    pub(super) fn push(&mut self, line: &str) {
+        self.data.push_str(line);
+        self.data.push('\n');
+
+        if self.data.len() > self.limit.saturating_mul(2) {
+            self.cut();
+        }
+    }
+
+    // where the last `limit` bytes start
+    fn start(&self) -> usize {
+        let mut start = self.data.len().saturating_sub(self.limit);
+        while !self.data.is_char_boundary(start) {
+            start += 1;
+        }
+
+        start
+    }
+
+    fn cut(&mut self) {
+        let start = self.start();
+
+        if start > 0 {
+            self.data.drain(..start);
+            self.truncated = true;
+        }
+    }
+
+    // the captured text and whether anything was cut off the front
+    pub(super) fn take(&mut self) -> (String, bool) {
+        self.cut();
+
+        (
+            std::mem::take(&mut self.data),
+            std::mem::take(&mut self.truncated),
+        )
+    }
+
+    pub(super) async fn read(
+        &mut self,
+        pipe: impl AsyncRead + Unpin,
+        mut on_line: Option<&mut LineCallback>,
+    ) -> std::io::Result<()> {
+        let mut reader = BufReader::new(pipe);
+        let mut buf = vec![];
+
+        // not `lines()`: that one gives up on non-utf8 output
+        while reader.read_until(b'\n', &mut buf).await? > 0 {
+            let line = String::from_utf8_lossy(&buf);
+            let line = line.trim_end_matches(['\n', '\r']);
+
+            if let Some(on_line) = on_line.as_mut() {
+                on_line(line);
+            }
+            self.push(line);
+
+            buf.clear();
+        }
+
+        Ok(())
+    }
+
+    pub(super) fn tail(&self, lines: usize) -> String {
+        let mut tail = self.data[self.start()..]
+            .lines()
+            .rev()
+            .take(lines)
+            .collect::<Vec<_>>();
+        tail.reverse();
+
+        tail.join("\n")
+    }
+}
+
+// nobody gets to write to it here, and a child reading it would wait forever
+pub(super) fn piped_stdin() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        "stdin can't be piped here, pass it with RunOptions::stdin",
+    )
+}
+
+pub async fn run(cmdline: &Cmdline, mut options: RunOptions) -> Result<RunOutput, ExitError> {
+    let started = Instant::now();
+
+    let mut cmdline = cmdline.clone();
+    if let Some(stdin) = options.stdin.take() {
+        cmdline.stdin = StdinSource::Bytes(stdin);
+    }
+
+    if cmdline.stdin == StdinSource::Pipe {
+        return Err(ExitError::Spawn(piped_stdin()));
+    }
+
+    let mut child = cmdline
+        .to_command()
+        .map_err(ExitError::Spawn)?
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(ExitError::Spawn)?;
+
+    let feeder = cmdline.stdin_feeder(&mut child);
+    let stdout = child.stdout.take().unwrap();
+    let stderr = child.stderr.take().unwrap();
+
+    let mut out = Capture::new(options.capture_limit);
+    let mut err = Capture::new(options.capture_limit);
+
+    let timeout = options.timeout;
+    let stop = options.stop.take();
+
+    let result = {
+        let work = async {
+            let (out_result, err_result, _, status) = tokio::join!(
+                out.read(stdout, options.on_stdout_line.as_mut()),
+                err.read(stderr, options.on_stderr_line.as_mut()),
+                async {
+                    if let Some(feeder) = feeder {
+                        // the child is free to not read its stdin
+                        let _ = feeder.await;
+                    }
+                },
+                child.wait(),
+            );
+
+            out_result?;
+            err_result?;
+            status
+        };
+
+        let timed_out = async {
+            match timeout {
+                Some(timeout) => tokio::time::sleep(timeout).await,
+                None => std::future::pending().await,
+            }
+        };
+        let stopped = async {
+            match &stop {
+                Some(stop) => stop.notified().await,
+                None => std::future::pending().await,
+            }
+        };
+
+        tokio::select! {
+            status = work => Ok(status),
+            () = timed_out => Err(true),
+            () = stopped => Err(false),
+        }
+    };
+
+    let status = match result {
+        Ok(status) => status.map_err(ExitError::Io)?,
+        Err(timed_out) => {
+            terminate(&mut child, options.kill_grace)
+                .await
+                .map_err(ExitError::Io)?;
+
+            let stderr_tail = err.tail(STDERR_TAIL_LINES);
+
+            return Err(if timed_out {
+                ExitError::TimedOut {
+                    after: started.elapsed(),
+                    stderr_tail,
+                }
+            } else {
+                ExitError::Stopped { stderr_tail }
+            });
+        }
+    };
+
+    if !status.success() {
+        return Err(ExitError::Failed {
+            status,
+            stderr_tail: err.tail(STDERR_TAIL_LINES),
+        });
+    }
+
+    let (stdout, stdout_truncated) = out.take();
+    let (stderr, stderr_truncated) = err.take();
+
+    Ok(RunOutput {
+        status,
+        stdout,
+        stderr,
+        stdout_truncated,
+        stderr_truncated,
+        duration: started.elapsed(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn captures_and_fails() -> anyhow::Result<()> {
+        let echo = Cmdline::program("sh").args(["-c", "cat; echo oops >&2"]);
+
+        let output = run(&echo, RunOptions::default().stdin("hello\n")).await?;
+        assert_eq!(output.stdout, "hello\n");
+        assert_eq!(output.stderr, "oops\n");
+
+        let failing = Cmdline::program("sh").args(["-c", "echo broken >&2; exit 3"]);
+        let Err(ExitError::Failed {
+            status,
+            stderr_tail,
+        }) = run(&failing, RunOptions::default()).await
+        else {
+            panic!("should fail");
+        };
+        assert_eq!(status.code(), Some(3));
+        assert_eq!(stderr_tail, "broken");
+
+        let sleepy = Cmdline::program("sleep").arg("10");
+        let result = run(
+            &sleepy,
+            RunOptions::default().timeout(Duration::from_millis(100)),
+        )
+        .await;
+        assert!(matches!(result, Err(ExitError::TimedOut { .. })));
+
+        let piped = Cmdline::program("cat").stdin(StdinSource::Pipe);
+        let Err(ExitError::Spawn(e)) = run(&piped, RunOptions::default()).await else {
+            panic!("should refuse");
+        };
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_the_end_of_long_output() {
+        let mut capture = Capture::new(11);
+        for i in 0..1000 {
+            capture.push(&format!("é{}", i));
+        }
+        // never much more than the limit in between
+        assert!(capture.data.len() <= 22 + "é999\n".len());
+        assert_eq!(capture.tail(1), "é999");
+
+        let (data, truncated) = capture.take();
+        assert!(truncated);
+        // the 11th byte from the end is in the middle of an "é"
+        assert_eq!(data, "998\né999\n");
+
+        let mut capture = Capture::new(10);
+        capture.push("short");
+        assert_eq!(capture.take(), ("short\n".to_owned(), false));
+    }
+}
@@ -0,0 +1,492 @@
+use std::{
+    collections::{hash_map::RandomState, VecDeque},
+    hash::{BuildHasher, Hasher},
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{Mutex, Notify},
+    task::JoinHandle,
+};
+
+use super::{run, run::Capture, Cmdline, ExitError, RunOptions, DEFAULT_KILL_GRACE};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Schedule {
+    // 5 fields (minute precision) or 6-7 fields starting with seconds
+    Cron(String),
+    Every(#[serde(with = "humantime_serde")] Duration),
+}
+
+impl Schedule {
+    fn cron(expr: &str) -> anyhow::Result<cron::Schedule> {
+        let expr = if expr.split_whitespace().count() == 5 {
+            format!("0 {}", expr)
+        } else {
+            expr.to_owned()
+        };
+
+        cron::Schedule::from_str(&expr).map_err(|e| anyhow::anyhow!("bad cron {:?}: {}", expr, e))
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        match self {
+            Schedule::Cron(expr) => Self::cron(expr).map(|_| ()),
+            Schedule::Every(every) if every.is_zero() => {
+                Err(anyhow::anyhow!("schedule interval must be positive"))
+            }
+            Schedule::Every(_) => Ok(()),
+        }
+    }
+
+    pub fn next_run(&self) -> anyhow::Result<SystemTime> {
+        match self {
+            Schedule::Cron(expr) => {
+                let next = Self::cron(expr)?
+                    .upcoming(chrono::Local)
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("{:?} never fires again", expr))?;
+
+                Ok(next.into())
+            }
+            Schedule::Every(every) => Ok(SystemTime::now() + *every),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverlapPolicy {
+    #[default]
+    Skip,
+    // at most one run waits for the current one
+    Queue,
+    KillPrevious,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RunRecord {
+    #[serde(with = "humantime_serde")]
+    pub started_at: SystemTime,
+    #[serde(with = "humantime_serde")]
+    pub finished_at: Option<SystemTime>,
+    // `None` while running
+    pub success: Option<bool>,
+    pub status: String,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+#[derive(Debug, Default)]
+struct RunState {
+    running: bool,
+    queued: bool,
+}
+
+// locks are taken in field order: `current`, then `state`, then `runs`
+#[derive(Debug)]
+pub struct ScheduledJob {
+    pub worker_id: String,
+    pub name: String,
+    pub cmdline: Cmdline,
+    pub schedule: Schedule,
+    pub overlap: OverlapPolicy,
+    pub jitter: Duration,
+    pub timeout: Option<Duration>,
+    pub kill_grace: Duration,
+    pub capture_limit: usize,
+    pub history: usize,
+    pub enabled: Mutex<bool>,
+    pub runs: Mutex<VecDeque<RunRecord>>,
+    next_run: Mutex<Option<SystemTime>>,
+    // the running loop, and what stops its run
+    current: Mutex<Option<(JoinHandle<()>, Arc<Notify>)>>,
+    // a run finishing and a trigger queueing the next one hand off under this lock
+    state: Mutex<RunState>,
+}
+
+fn random_below(max: Duration) -> Duration {
+    if max.is_zero() {
+        return Duration::ZERO;
+    }
+
+    let random = RandomState::new().build_hasher().finish();
+    Duration::from_nanos(random % max.as_nanos().min(u64::MAX as u128) as u64)
+}
+
+impl ScheduledJob {
+    pub fn new(
+        worker_id: String,
+        name: String,
+        cmdline: Cmdline,
+        schedule: Schedule,
+    ) -> anyhow::Result<Self> {
+        schedule.validate()?;
+
+        Ok(Self {
+            worker_id,
+            name,
+            cmdline,
+            schedule,
+            overlap: OverlapPolicy::default(),
+            jitter: Duration::ZERO,
+            timeout: None,
+            kill_grace: DEFAULT_KILL_GRACE,
+            capture_limit: 64 << 10,
+            history: 20,
+            enabled: Mutex::new(true),
+            runs: Mutex::new(VecDeque::new()),
+            next_run: Mutex::new(None),
+            current: Mutex::new(None),
+            state: Mutex::default(),
+        })
+    }
+
+    pub fn with_overlap(mut self, overlap: OverlapPolicy) -> Self {
+        self.overlap = overlap;
+        self
+    }
+
+    pub fn with_jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_kill_grace(mut self, kill_grace: Duration) -> Self {
+        self.kill_grace = kill_grace;
+        self
+    }
+
+    pub fn with_history(mut self, history: usize) -> Self {
+        self.history = history;
+        self
+    }
+
+    pub async fn schedule_loop(self: Arc<Self>) -> anyhow::Result<()> {
+        loop {
+            if !*self.enabled.lock().await {
+                println!("{} is disabled, not scheduling", self.name);
+                return Ok(());
+            }
+
+            let next = self.schedule.next_run()? + random_below(self.jitter);
+            *self.next_run.lock().await = Some(next);
+
+            let wait = next.duration_since(SystemTime::now()).unwrap_or_default();
+            tokio::time::sleep(wait).await;
+
+            if *self.enabled.lock().await {
+                self.clone().trigger().await;
+            }
+        }
+    }
+
+    pub async fn disable(&self) {
+        *self.enabled.lock().await = false;
+        *self.next_run.lock().await = None;
+    }
+
+    pub async fn enable(&self) {
+        *self.enabled.lock().await = true;
+    }
+
+    pub async fn is_running(&self) -> bool {
+        self.state.lock().await.running
+    }
+
+    pub async fn trigger(self: Arc<Self>) {
+        let mut current = self.current.lock().await;
+        let mut state = self.state.lock().await;
+
+        if state.running {
+            match self.overlap {
+                OverlapPolicy::Skip => {
+                    println!("{} is still running, skipping this run", self.name);
+                    return;
+                }
+                OverlapPolicy::Queue => {
+                    println!("{} is still running, queueing the next run", self.name);
+                    state.queued = true;
+                    return;
+                }
+                OverlapPolicy::KillPrevious => {
+                    println!("{} is still running, killing it", self.name);
+
+                    state.queued = false;
+
+                    // the loop takes `state` once the run is done, so it's let go while
+                    // waiting; holding `current` keeps other triggers out meanwhile
+                    if let Some((handle, stop)) = current.take() {
+                        stop.notify_one();
+                        drop(state);
+                        let _ = handle.await;
+                        state = self.state.lock().await;
+                    }
+                }
+            }
+        }
+
+        state.running = true;
+        drop(state);
+
+        let job = self.clone();
+        let stop = Arc::new(Notify::new());
+        let handle = tokio::spawn({
+            let stop = stop.clone();
+            async move {
+                loop {
+                    job.run_once(stop.clone()).await;
+
+                    let mut state = job.state.lock().await;
+                    if !state.queued {
+                        state.running = false;
+                        return;
+                    }
+                    state.queued = false;
+                }
+            }
+        });
+        *current = Some((handle, stop));
+    }
+
+    async fn run_once(&self, stop: Arc<Notify>) {
+        {
+            let mut runs = self.runs.lock().await;
+            runs.push_back(RunRecord {
+                started_at: SystemTime::now(),
+                finished_at: None,
+                success: None,
+                status: "running".to_owned(),
+                stdout: String::new(),
+                stderr: String::new(),
+            });
+            while runs.len() > self.history {
+                runs.pop_front();
+            }
+        }
+
+        println!("running scheduled {}", self.name);
+
+        let stdout = Arc::new(std::sync::Mutex::new(Capture::new(self.capture_limit)));
+        let stderr = Arc::new(std::sync::Mutex::new(Capture::new(self.capture_limit)));
+
+        let mut options = RunOptions::default()
+            .kill_grace(self.kill_grace)
+            .stop_on(stop)
+            .capture_limit(self.capture_limit)
+            .on_stdout_line({
+                let stdout = stdout.clone();
+                move |line| stdout.lock().unwrap().push(line)
+            })
+            .on_stderr_line({
+                let stderr = stderr.clone();
+                move |line| stderr.lock().unwrap().push(line)
+            });
+
+        if let Some(timeout) = self.timeout {
+            options = options.timeout(timeout);
+        }
+
+        let cmdline = self.cmdline.clone().env("WORKER_ID", &self.worker_id);
+        let result = run(&cmdline, options).await;
+
+        let (success, status) = match &result {
+            Ok(output) => (true, output.status.to_string()),
+            Err(ExitError::Failed { status, .. }) => (false, status.to_string()),
+            Err(ExitError::Stopped { .. }) => (false, "killed by the next run".to_owned()),
+            Err(e) => (false, e.to_string()),
+        };
+
+        println!("scheduled {} finished: {}", self.name, status);
+
+        if let Some(record) = self.runs.lock().await.back_mut() {
+            record.finished_at = Some(SystemTime::now());
+            record.success = Some(success);
+            record.status = status;
+            record.stdout = stdout.lock().unwrap().take().0;
+            record.stderr = stderr.lock().unwrap().take().0;
+        }
+    }
+
+    pub async fn get_status(&self) -> anyhow::Result<serde_json::Value> {
+        // before `runs`, see the lock order above
+        let running = self.is_running().await;
+        let runs = self.runs.lock().await;
+        let last = runs.back();
+
+        Ok(serde_json::json!({
+            "process_id": self.worker_id,
+            "name": self.name,
+            "running": running,
+            "stdout": last.map(|run| run.stdout.as_str()).unwrap_or_default(),
+            "stderr": last.map(|run| run.stderr.as_str()).unwrap_or_default(),
+            "schedule": self.schedule,
+            "overlap": self.overlap,
+            "next_run": self
+                .next_run
+                .lock()
+                .await
+                .map(|next| chrono::DateTime::<chrono::Local>::from(next).to_rfc3339()),
+            "runs": *runs,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn skips_overlapping_runs() -> anyhow::Result<()> {
+        assert!(Schedule::Cron("*/5 * * * *".to_owned()).next_run().is_ok());
+        assert!(Schedule::Cron("nonsense".to_owned()).validate().is_err());
+
+        let job = Arc::new(ScheduledJob::new(
+            "test".to_owned(),
+            "sleepy".to_owned(),
+            Cmdline::program("sh").args(["-c", "echo hi; sleep 0.3"]),
+            Schedule::Every(Duration::from_secs(3600)),
+        )?);
+
+        job.clone().trigger().await;
+        job.clone().trigger().await;
+
+        tokio::time::sleep(Duration::from_millis(600)).await;
+
+        let runs = job.runs.lock().await;
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].success, Some(true));
+        assert_eq!(runs[0].stdout, "hi\n");
+
+        Ok(())
+    }
+
+    fn job(script: &str, overlap: OverlapPolicy) -> anyhow::Result<Arc<ScheduledJob>> {
+        Ok(Arc::new(
+            ScheduledJob::new(
+                "test".to_owned(),
+                "job".to_owned(),
+                Cmdline::program("sh").args(["-c", script]),
+                Schedule::Every(Duration::from_secs(3600)),
+            )?
+            .with_overlap(overlap),
+        ))
+    }
+
+    async fn settle(job: &ScheduledJob) {
+        while job.is_running().await {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn status_and_kill_previous_dont_deadlock() -> anyhow::Result<()> {
+        let job = job("sleep 0.02", OverlapPolicy::KillPrevious)?;
+
+        let triggers = tokio::spawn({
+            let job = job.clone();
+            async move {
+                for _ in 0..30 {
+                    job.clone().trigger().await;
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                }
+            }
+        });
+
+        let statuses = tokio::spawn({
+            let job = job.clone();
+            async move {
+                for _ in 0..300 {
+                    job.get_status().await?;
+                }
+                anyhow::Ok(())
+            }
+        });
+
+        tokio::time::timeout(Duration::from_secs(10), async {
+            triggers.await?;
+            statuses.await?
+        })
+        .await??;
+
+        settle(&job).await;
+        assert!(job
+            .runs
+            .lock()
+            .await
+            .iter()
+            .all(|run| run.finished_at.is_some()));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn kill_previous_terminates_gracefully() -> anyhow::Result<()> {
+        let marker = std::env::temp_dir().join(format!("kill-previous-{}", std::process::id()));
+        let script = format!(
+            "trap 'touch {}; kill $!; exit 3' TERM; sleep 5 & wait",
+            marker.display()
+        );
+
+        let job = Arc::new(
+            ScheduledJob::new(
+                "test".to_owned(),
+                "job".to_owned(),
+                Cmdline::program("sh").args(["-c", &script]),
+                Schedule::Every(Duration::from_secs(3600)),
+            )?
+            .with_overlap(OverlapPolicy::KillPrevious)
+            .with_timeout(Duration::from_millis(500)),
+        );
+
+        job.clone().trigger().await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        job.clone().trigger().await;
+        settle(&job).await;
+
+        let runs = job.runs.lock().await;
+        assert_eq!(runs.len(), 2);
+        assert_eq!(runs[0].status, "killed by the next run");
+        // the trap only runs if it got SIGTERM before SIGKILL
+        assert!(marker.exists());
+        std::fs::remove_file(&marker)?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn queues_without_losing_runs() -> anyhow::Result<()> {
+        let job = job("sleep 0.2", OverlapPolicy::Queue)?;
+
+        job.clone().trigger().await;
+        job.clone().trigger().await;
+        job.clone().trigger().await;
+        settle(&job).await;
+        // the two queued triggers make one more run
+        assert_eq!(job.runs.lock().await.len(), 2);
+
+        // racing triggers against runs that end right away
+        let job = self::job("true", OverlapPolicy::Queue)?;
+        for _ in 0..200 {
+            job.clone().trigger().await;
+            tokio::task::yield_now().await;
+        }
+        settle(&job).await;
+        assert!(!job.state.lock().await.queued);
+
+        let runs = job.runs.lock().await.len();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(job.runs.lock().await.len(), runs);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,117 @@
+use std::{collections::VecDeque, sync::Arc};
+
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::{Mutex, Notify},
+};
+
+// outlives any single child: whatever wasn't written yet goes to the next one
+#[derive(Debug)]
+pub struct StdinQueue {
+    pub capacity: usize,
+    queue: Mutex<VecDeque<Vec<u8>>>,
+    pushed: Notify,
+}
+
+impl StdinQueue {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            queue: Mutex::new(VecDeque::new()),
+            pushed: Notify::new(),
+        }
+    }
+
+    pub async fn push(&self, data: Vec<u8>) -> anyhow::Result<()> {
+        let mut queue = self.queue.lock().await;
+
+        if queue.len() >= self.capacity {
+            return Err(anyhow::anyhow!(
+                "stdin queue is full ({} messages)",
+                self.capacity
+            ));
+        }
+
+        queue.push_back(data);
+        self.pushed.notify_one();
+
+        Ok(())
+    }
+
+    pub async fn len(&self) -> usize {
+        self.queue.lock().await.len()
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.queue.lock().await.is_empty()
+    }
+
+    pub async fn clear(&self) {
+        self.queue.lock().await.clear();
+    }
+
+    // one writer per child; a message only leaves the queue once it's written, so one that
+    // was cut short by a restart is sent again in full
+    pub(super) async fn run_writer(
+        self: Arc<Self>,
+        mut stdin: impl AsyncWrite + Unpin,
+    ) -> anyhow::Result<()> {
+        loop {
+            let front = self.queue.lock().await.front().cloned();
+
+            let Some(data) = front else {
+                self.pushed.notified().await;
+                continue;
+            };
+
+            stdin.write_all(&data).await?;
+            stdin.flush().await?;
+
+            self.queue.lock().await.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn keeps_unwritten_messages() -> anyhow::Result<()> {
+        let queue = Arc::new(StdinQueue::new(2));
+        queue.push(b"one\n".to_vec()).await?;
+        queue.push(b"two\n".to_vec()).await?;
+        assert!(queue.push(b"three\n".to_vec()).await.is_err());
+
+        // a child that's stuck halfway through the first message
+        let (stdin, _stuck) = tokio::io::duplex(2);
+        let writer = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.run_writer(stdin).await }
+        });
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        writer.abort();
+        assert_eq!(queue.len().await, 2);
+
+        let (stdin, mut child) = tokio::io::duplex(64);
+        let writer = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.run_writer(stdin).await }
+        });
+
+        let mut read = [0; 8];
+        child.read_exact(&mut read).await?;
+        assert_eq!(&read, b"one\ntwo\n");
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(queue.is_empty().await);
+        writer.abort();
+
+        Ok(())
+    }
+}
@@ -0,0 +1,664 @@
+use std::{path::PathBuf, sync::Arc};
+
+use tokio::{sync::Mutex, task::JoinHandle};
+
+use super::{
+    config::start_order, Journal, JournalEntry, JournalEvent, JournalQuery, ProcessConfig,
+    ProcessRunner, SupervisorConfig,
+};
+
+#[derive(Debug)]
+pub struct Supervised {
+    pub config: ProcessConfig,
+    pub runner: Arc<ProcessRunner>,
+    looper: Option<JoinHandle<anyhow::Result<()>>>,
+}
+
+impl Supervised {
+    fn is_looping(&self) -> bool {
+        self.looper
+            .as_ref()
+            .is_some_and(|looper| !looper.is_finished())
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ReloadSummary {
+    pub started: Vec<String>,
+    pub stopped: Vec<String>,
+    pub restarted: Vec<String>,
+    // name and error, for the ones that were left as they were
+    pub failed: Vec<(String, String)>,
+}
+
+#[derive(Debug)]
+pub struct Supervisor {
+    pub worker_id: String,
+    pub state_dir: PathBuf,
+    pub processes: Mutex<Vec<Supervised>>,
+}
+
+impl Supervisor {
+    pub fn new(worker_id: String, state_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            worker_id,
+            state_dir: state_dir.into(),
+            processes: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn from_config(config: SupervisorConfig) -> anyhow::Result<Self> {
+        config.validate()?;
+
+        let supervisor = Self::new(
+            config.worker_id,
+            config.state_dir.unwrap_or_else(|| PathBuf::from(".")),
+        );
+
+        let processes = config
+            .processes
+            .into_iter()
+            .map(|config| supervisor.supervised(config))
+            .collect::<anyhow::Result<_>>()?;
+
+        *supervisor.processes.try_lock()? = processes;
+
+        Ok(supervisor)
+    }
+
+    pub fn load(path: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        Self::from_config(SupervisorConfig::load(path.into())?)
+    }
+
+    fn supervised(&self, config: ProcessConfig) -> anyhow::Result<Supervised> {
+        let mut runner = ProcessRunner::with_state_dir(
+            self.worker_id.clone(),
+            config.name.clone(),
+            config.cmdline.clone(),
+            &self.state_dir,
+        )?
+        .with_restart_policy(config.restart)
+        .with_metrics(config.metrics.clone());
+
+        if let Some(health_check) = &config.health_check {
+            runner = runner.with_health_check(health_check.clone());
+        }
+
+        if let Some(readiness) = &config.ready_when {
+            runner = runner.with_readiness(readiness.clone());
+        }
+
+        if let Some(size) = config.pty {
+            runner = runner.with_pty(size);
+        }
+
+        Ok(Supervised {
+            config,
+            runner: Arc::new(runner),
+            looper: None,
+        })
+    }
+
+    pub async fn add(&self, config: ProcessConfig) -> anyhow::Result<()> {
+        let mut processes = self.processes.lock().await;
+
+        // before building the runner, so a rejected one can't touch anything
+        Self::check_unique(&processes, &config.name)?;
+        let supervised = self.supervised(config)?;
+
+        Self::push(&mut processes, supervised)
+    }
+
+    // for runners that were built by hand rather than from a config
+    pub async fn adopt(&self, runner: ProcessRunner) -> anyhow::Result<()> {
+        let config = ProcessConfig {
+            name: runner.name.clone(),
+            cmdline: runner.cmdline.clone(),
+            restart: runner.restart_policy,
+            health_check: runner.health_check.clone(),
+            metrics: runner.metrics.clone(),
+            depends_on: vec![],
+            ready_when: runner.readiness.clone(),
+            pty: runner.pty,
+        };
+
+        Self::push(
+            &mut *self.processes.lock().await,
+            Supervised {
+                config,
+                runner: Arc::new(runner),
+                looper: None,
+            },
+        )
+    }
+
+    fn check_unique(processes: &[Supervised], name: &str) -> anyhow::Result<()> {
+        if processes.iter().any(|p| p.config.name == name) {
+            return Err(anyhow::anyhow!("duplicate process name: {}", name));
+        }
+
+        Ok(())
+    }
+
+    fn push(processes: &mut Vec<Supervised>, supervised: Supervised) -> anyhow::Result<()> {
+        Self::check_unique(processes, &supervised.config.name)?;
+        processes.push(supervised);
+
+        Ok(())
+    }
+
+    pub async fn names(&self) -> Vec<String> {
+        self.processes
+            .lock()
+            .await
+            .iter()
+            .map(|p| p.config.name.clone())
+            .collect()
+    }
+
+    pub async fn runners(&self) -> Vec<Arc<ProcessRunner>> {
+        self.processes
+            .lock()
+            .await
+            .iter()
+            .map(|p| p.runner.clone())
+            .collect()
+    }
+
+    pub async fn runner(&self, name: &str) -> Option<Arc<ProcessRunner>> {
+        self.processes
+            .lock()
+            .await
+            .iter()
+            .find(|p| p.config.name == name)
+            .map(|p| p.runner.clone())
+    }
+
+    async fn start_supervised(supervised: &mut Supervised) -> anyhow::Result<()> {
+        if supervised.is_looping() {
+            return Ok(());
+        }
+
+        supervised.runner.enable_restart().await?;
+        supervised.looper = Some(tokio::spawn(supervised.runner.clone().start_and_loop()));
+
+        Ok(())
+    }
+
+    async fn stop_supervised(supervised: &mut Supervised) -> anyhow::Result<()> {
+        Self::stop_runner(
+            &supervised.config.name,
+            &supervised.runner,
+            &mut supervised.looper,
+        )
+        .await
+    }
+
+    async fn stop_runner(
+        name: &str,
+        runner: &ProcessRunner,
+        looper: &mut Option<JoinHandle<anyhow::Result<()>>>,
+    ) -> anyhow::Result<()> {
+        runner.disable_restart().await?;
+        runner.stop().await?;
+
+        if let Some(looper) = looper.take() {
+            if let Err(e) = looper.await? {
+                println!("{} exited with error: {}", name, e);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub async fn start_order(&self) -> anyhow::Result<Vec<String>> {
+        let configs = self
+            .processes
+            .lock()
+            .await
+            .iter()
+            .map(|p| p.config.clone())
+            .collect::<Vec<_>>();
+
+        start_order(&configs)
+    }
+
+    pub async fn start_all(&self) -> anyhow::Result<()> {
+        for name in self.start_order().await? {
+            self.start_process(&name).await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn stop_all(&self) -> anyhow::Result<()> {
+        for name in self.start_order().await?.iter().rev() {
+            self.stop_process(name).await?;
+        }
+
+        Ok(())
+    }
+
+    // without holding the process list, so status stays available meanwhile
+    async fn wait_for_dependencies(&self, name: &str) -> anyhow::Result<()> {
+        let dependencies = {
+            let mut processes = self.processes.lock().await;
+            let depends_on = Self::find(&mut processes, name)?.config.depends_on.clone();
+
+            depends_on
+                .iter()
+                .map(|dep| Ok(Self::find(&mut processes, dep)?.runner.clone()))
+                .collect::<anyhow::Result<Vec<_>>>()?
+        };
+
+        for dependency in dependencies {
+            if !dependency.is_ready() {
+                println!("{} is waiting for {} to be ready", name, dependency.name);
+            }
+
+            dependency.wait_ready().await?;
+        }
+
+        Ok(())
+    }
+
+    pub fn journal(&self) -> Journal {
+        Journal::in_state_dir(&self.state_dir)
+    }
+
+    pub async fn query_journal(&self, query: &JournalQuery) -> anyhow::Result<Vec<JournalEntry>> {
+        self.journal().query(query).await
+    }
+
+    // start/stop/restart are what operators call, so they end up in the journal as commands
+    pub async fn start(&self, name: &str) -> anyhow::Result<()> {
+        self.journal()
+            .record(name, JournalEvent::command("start"))
+            .await;
+        self.start_process(name).await
+    }
+
+    pub async fn stop(&self, name: &str) -> anyhow::Result<()> {
+        self.journal()
+            .record(name, JournalEvent::command("stop"))
+            .await;
+        self.stop_process(name).await
+    }
+
+    async fn start_process(&self, name: &str) -> anyhow::Result<()> {
+        self.wait_for_dependencies(name).await?;
+
+        let mut processes = self.processes.lock().await;
+        let supervised = Self::find(&mut processes, name)?;
+
+        Self::start_supervised(supervised).await
+    }
+
+    async fn stop_process(&self, name: &str) -> anyhow::Result<()> {
+        let mut processes = self.processes.lock().await;
+        let supervised = Self::find(&mut processes, name)?;
+
+        Self::stop_supervised(supervised).await
+    }
+
+    pub async fn restart(&self, name: &str) -> anyhow::Result<()> {
+        self.journal()
+            .record(name, JournalEvent::command("restart"))
+            .await;
+
+        let mut processes = self.processes.lock().await;
+        let supervised = Self::find(&mut processes, name)?;
+
+        if !supervised.is_looping() {
+            return Self::start_supervised(supervised).await;
+        }
+
+        // the loop starts the next one; not holding the list through the kill grace
+        let runner = supervised.runner.clone();
+        drop(processes);
+
+        runner.restart_looping().await
+    }
+
+    fn find<'a>(processes: &'a mut [Supervised], name: &str) -> anyhow::Result<&'a mut Supervised> {
+        processes
+            .iter_mut()
+            .find(|p| p.config.name == name)
+            .ok_or_else(|| anyhow::anyhow!("no such process: {}", name))
+    }
+
+    pub async fn get_status(&self) -> anyhow::Result<serde_json::Value> {
+        let mut statuses = vec![];
+
+        for supervised in self.processes.lock().await.iter() {
+            statuses.push(supervised.runner.get_status().await?);
+        }
+
+        Ok(serde_json::json!(statuses))
+    }
+
+    // only touches the processes whose config changed, and whatever depends on them; one
+    // process failing doesn't stop the rest
+    pub async fn reload(&self, config: SupervisorConfig) -> anyhow::Result<ReloadSummary> {
+        config.validate()?;
+
+        let order = start_order(&config.processes)?;
+        let mut summary = ReloadSummary::default();
+        let mut to_start = vec![];
+
+        // the affected ones are taken out under the lock and stopped without it, so status
+        // and the other calls don't wait on their grace periods
+        let mut stopping = {
+            let mut processes = self.processes.lock().await;
+
+            let mut affected = processes
+                .iter()
+                .filter(|p| config.process(&p.config.name) != Some(&p.config))
+                .map(|p| p.config.name.clone())
+                .collect::<Vec<_>>();
+
+            // running dependents go down before their dependency and come back up after it
+            loop {
+                let dependents = config
+                    .processes
+                    .iter()
+                    .filter(|process| !affected.contains(&process.name))
+                    .filter(|process| process.depends_on.iter().any(|dep| affected.contains(dep)))
+                    .filter(|process| {
+                        processes
+                            .iter()
+                            .any(|p| p.config.name == process.name && p.is_looping())
+                    })
+                    .map(|process| process.name.clone())
+                    .collect::<Vec<_>>();
+
+                if dependents.is_empty() {
+                    break;
+                }
+
+                affected.extend(dependents);
+            }
+
+            let old_configs = processes
+                .iter()
+                .map(|p| p.config.clone())
+                .collect::<Vec<_>>();
+            let stop_order = start_order(&old_configs)
+                .unwrap_or_else(|_| old_configs.iter().map(|p| p.name.clone()).collect());
+
+            // everything stays in the list until it's actually stopped, so nothing runs untracked
+            let mut stopping = vec![];
+
+            for name in stop_order
+                .iter()
+                .rev()
+                .filter(|name| affected.contains(name))
+            {
+                let supervised = Self::find(&mut processes, name)?;
+                let running = supervised.is_looping();
+
+                stopping.push((
+                    name.clone(),
+                    supervised.runner.clone(),
+                    supervised.looper.take(),
+                    running,
+                ));
+            }
+
+            stopping
+        };
+
+        let mut stopped = vec![];
+
+        for (name, runner, looper, running) in &mut stopping {
+            match config.process(name) {
+                Some(_) => println!("reload: restarting {}", name),
+                None => println!("reload: stopping {}", name),
+            }
+
+            match Self::stop_runner(name, runner, looper).await {
+                Ok(()) => stopped.push((name.clone(), *running)),
+                Err(e) => summary.failed.push((name.clone(), e.to_string())),
+            }
+        }
+
+        {
+            let mut processes = self.processes.lock().await;
+
+            // whatever failed to stop keeps its loop
+            for (name, _, looper, _) in stopping {
+                if let Some(looper) = looper {
+                    if let Ok(supervised) = Self::find(&mut processes, &name) {
+                        supervised.looper = Some(looper);
+                    }
+                }
+            }
+
+            let was_stopped = |name: &String| stopped.iter().any(|(n, _)| n == name);
+            let was_running = |name: &String| stopped.iter().any(|(n, r)| n == name && *r);
+
+            processes.retain(|p| {
+                let name = &p.config.name;
+
+                if config.process(name).is_none() && was_stopped(name) {
+                    summary.stopped.push(name.clone());
+                    return false;
+                }
+
+                true
+            });
+
+            for process in &config.processes {
+                let existing = processes.iter().position(|p| p.config.name == process.name);
+
+                match existing {
+                    Some(i) if processes[i].config == *process => {
+                        if was_running(&process.name) {
+                            to_start.push((process.name.clone(), true));
+                        }
+                    }
+                    // still running the old config
+                    Some(_) if !was_stopped(&process.name) => {}
+                    _ => match self.supervised(process.clone()) {
+                        Ok(supervised) => {
+                            match existing {
+                                Some(i) => processes[i] = supervised,
+                                None => processes.push(supervised),
+                            }
+
+                            // changed ones come back only if they were running before
+                            if existing.is_none() || was_running(&process.name) {
+                                to_start.push((process.name.clone(), existing.is_some()));
+                            }
+                        }
+                        Err(e) => summary.failed.push((process.name.clone(), e.to_string())),
+                    },
+                }
+            }
+
+            // config order, with whatever failed to stop at the end
+            processes.sort_by_key(|p| {
+                config
+                    .processes
+                    .iter()
+                    .position(|process| process.name == p.config.name)
+                    .unwrap_or(usize::MAX)
+            });
+        }
+
+        // the same way as `start_all`: dependencies first, each waiting for them to be ready
+        for name in &order {
+            let Some(&(_, restarted)) = to_start.iter().find(|(n, _)| n == name) else {
+                continue;
+            };
+
+            match self.start_process(name).await {
+                Ok(()) if restarted => summary.restarted.push(name.clone()),
+                Ok(()) => summary.started.push(name.clone()),
+                Err(e) => summary.failed.push((name.clone(), e.to_string())),
+            }
+        }
+
+        Ok(summary)
+    }
+
+    pub async fn reload_from(&self, path: impl Into<PathBuf>) -> anyhow::Result<ReloadSummary> {
+        self.reload(SupervisorConfig::load(path.into())?).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{path::Path, time::Duration};
+
+    use super::*;
+
+    fn config(state_dir: &Path, db_sleep: u32) -> anyhow::Result<SupervisorConfig> {
+        SupervisorConfig::from_toml(&format!(
+            r#"
+            worker_id = "test"
+            state_dir = "{}"
+
+            [[process]]
+            name = "app"
+            command = "sleep"
+            args = ["10"]
+            depends_on = ["db"]
+
+            [[process]]
+            name = "db"
+            command = "sh"
+            args = ["-c", "echo ready; exec sleep {}"]
+            ready_when = {{ type = "log_line", pattern = "ready" }}
+            "#,
+            state_dir.display(),
+            db_sleep
+        ))
+    }
+
+    async fn pid(supervisor: &Supervisor, name: &str) -> Option<u32> {
+        let runner = supervisor.runner(name).await?;
+        let pid = runner
+            .current
+            .lock()
+            .await
+            .as_ref()
+            .map(|running| running.pid);
+        pid
+    }
+
+    #[tokio::test]
+    async fn reload_restarts_dependents_in_order() -> anyhow::Result<()> {
+        let state_dir = std::env::temp_dir().join(format!("reload-test-{}", std::process::id()));
+        let supervisor = Supervisor::from_config(config(&state_dir, 10)?)?;
+
+        supervisor.start_all().await?;
+        supervisor.runner("app").await.unwrap().wait_ready().await?;
+        let app = pid(&supervisor, "app").await;
+        assert!(app.is_some());
+
+        let summary = supervisor.reload(config(&state_dir, 11)?).await?;
+        assert_eq!(summary.restarted, ["db", "app"]);
+        assert!(summary.failed.is_empty());
+
+        supervisor.runner("app").await.unwrap().wait_ready().await?;
+        assert!(supervisor.runner("db").await.unwrap().is_ready());
+        assert_ne!(pid(&supervisor, "app").await, app);
+        assert_eq!(supervisor.names().await, ["app", "db"]);
+
+        supervisor.stop_all().await?;
+        std::fs::remove_dir_all(&state_dir)?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reload_leaves_stopped_processes_stopped() -> anyhow::Result<()> {
+        let state_dir =
+            std::env::temp_dir().join(format!("reload-stopped-test-{}", std::process::id()));
+        let supervisor = Supervisor::from_config(config(&state_dir, 10)?)?;
+
+        let summary = supervisor.reload(config(&state_dir, 11)?).await?;
+        assert!(summary.restarted.is_empty());
+        assert!(summary.started.is_empty());
+        assert!(summary.failed.is_empty());
+
+        assert_eq!(pid(&supervisor, "db").await, None);
+        assert_eq!(pid(&supervisor, "app").await, None);
+        let reloaded = config(&state_dir, 11)?;
+        let processes = supervisor.processes.lock().await;
+        assert!(processes
+            .iter()
+            .all(|p| reloaded.process(&p.config.name) == Some(&p.config)));
+        drop(processes);
+
+        if state_dir.exists() {
+            std::fs::remove_dir_all(&state_dir)?;
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejected_add_kills_nothing() -> anyhow::Result<()> {
+        let state_dir = std::env::temp_dir().join(format!("add-test-{}", std::process::id()));
+        let config = config(&state_dir, 10)?;
+        let supervisor = Supervisor::from_config(config.clone())?;
+
+        supervisor.start("db").await?;
+        supervisor.runner("db").await.unwrap().wait_ready().await?;
+        let db = pid(&supervisor, "db").await.unwrap();
+
+        let e = supervisor
+            .add(config.process("db").unwrap().clone())
+            .await
+            .unwrap_err();
+        assert_eq!(e.to_string(), "duplicate process name: db");
+
+        // nor does reaping by hand, the pid-file points at our own child
+        ProcessRunner::reap_orphans("db", &state_dir)?;
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(pid(&supervisor, "db").await, Some(db));
+        assert_eq!(unsafe { libc::kill(db as libc::pid_t, 0) }, 0);
+
+        supervisor.stop_all().await?;
+        std::fs::remove_dir_all(&state_dir)?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn restart_spawns_once() -> anyhow::Result<()> {
+        let state_dir = std::env::temp_dir().join(format!("restart-test-{}", std::process::id()));
+        let supervisor = Supervisor::from_config(config(&state_dir, 10)?)?;
+
+        supervisor.start("db").await?;
+        let db = supervisor.runner("db").await.unwrap();
+        db.wait_ready().await?;
+        let first = pid(&supervisor, "db").await;
+
+        supervisor.restart("db").await?;
+        db.wait_ready().await?;
+        // time for a second start to show up, if there was going to be one
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        assert_ne!(pid(&supervisor, "db").await, first);
+        let journal = supervisor
+            .query_journal(&JournalQuery {
+                process: Some("db".to_owned()),
+                ..Default::default()
+            })
+            .await?;
+        let starts = journal
+            .iter()
+            .filter(|entry| matches!(entry.event, JournalEvent::Started { .. }))
+            .count();
+        assert_eq!(starts, 2);
+
+        supervisor.stop_all().await?;
+        std::fs::remove_dir_all(&state_dir)?;
+
+        Ok(())
+    }
+}
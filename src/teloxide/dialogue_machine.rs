@@ -0,0 +1,292 @@
+use std::{
+    fmt::{Debug, Display},
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use teloxide::{
+    dispatching::{dialogue::Storage, UpdateFilterExt, UpdateHandler},
+    prelude::Dialogue,
+    requests::Requester,
+    types::{Message, Update},
+    Bot,
+};
+
+use super::dialogue_state::{MutDialogueState, DEFAULT_LOCK_TIMEOUT};
+
+pub trait DialogueStates: Clone + Default + Debug + Send + Sync + 'static {
+    fn can_transition(&self, _next: &Self) -> bool {
+        true
+    }
+
+    // where `/cancel` and inactive chats end up
+    fn fallback() -> Self {
+        Self::default()
+    }
+}
+
+pub enum Transition<S> {
+    Stay,
+    Goto(S),
+    // back to the default state
+    Finish,
+}
+
+// what actually gets stored per chat
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MachineState<S> {
+    pub state: S,
+    pub last_activity_unix: u64,
+}
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+type Matcher<S> = Box<dyn Fn(&S) -> bool + Send + Sync>;
+type Handler<S> =
+    Box<dyn Fn(Bot, Message, S) -> BoxFuture<anyhow::Result<Transition<S>>> + Send + Sync>;
+
+pub struct DialogueMachine<S, St: ?Sized> {
+    storage: Arc<St>,
+    handlers: Vec<(Matcher<S>, Handler<S>)>,
+    inactivity_timeout: Option<Duration>,
+    lock_timeout: Duration,
+    cancel_reply: Option<String>,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn is_cancel(text: Option<&str>) -> bool {
+    text.and_then(|text| text.split_whitespace().next())
+        .and_then(|command| command.split('@').next())
+        == Some("/cancel")
+}
+
+impl<S, St> DialogueMachine<S, St>
+where
+    S: DialogueStates,
+    St: Storage<MachineState<S>> + ?Sized + Send + Sync + 'static,
+    St::Error: Display,
+{
+    pub fn new(storage: Arc<St>) -> Self {
+        Self {
+            storage,
+            handlers: vec![],
+            inactivity_timeout: None,
+            lock_timeout: DEFAULT_LOCK_TIMEOUT,
+            cancel_reply: None,
+        }
+    }
+
+    // the first handler whose matcher accepts the chat's state gets the message
+    pub fn on<F, Fut>(
+        mut self,
+        matches: impl Fn(&S) -> bool + Send + Sync + 'static,
+        handler: F,
+    ) -> Self
+    where
+        F: Fn(Bot, Message, S) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = anyhow::Result<Transition<S>>> + Send + 'static,
+    {
+        self.handlers.push((
+            Box::new(matches),
+            Box::new(move |bot, msg, state| Box::pin(handler(bot, msg, state))),
+        ));
+        self
+    }
+
+    pub fn inactivity_timeout(mut self, timeout: Duration) -> Self {
+        self.inactivity_timeout = Some(timeout);
+        self
+    }
+
+    // how long a message waits for the previous one of the same chat before failing
+    pub fn lock_timeout(mut self, timeout: Duration) -> Self {
+        self.lock_timeout = timeout;
+        self
+    }
+
+    pub fn cancel_reply(mut self, text: impl Into<String>) -> Self {
+        self.cancel_reply = Some(text.into());
+        self
+    }
+
+    pub fn handler(self: Arc<Self>) -> UpdateHandler<anyhow::Error> {
+        Update::filter_message().endpoint(move |bot: Bot, msg: Message| {
+            let machine = self.clone();
+            async move { machine.handle(bot, msg).await }
+        })
+    }
+
+    // the chat's state, with the inactivity timeout applied
+    fn resume(&self, stored: &MachineState<S>, now: u64) -> S {
+        let expired = self.inactivity_timeout.is_some_and(|timeout| {
+            stored.last_activity_unix != 0
+                && now.saturating_sub(stored.last_activity_unix) > timeout.as_secs()
+        });
+
+        if expired {
+            println!(
+                "dialogue inactive since {}, falling back",
+                stored.last_activity_unix
+            );
+            return S::fallback();
+        }
+
+        stored.state.clone()
+    }
+
+    fn apply(current: S, transition: Transition<S>) -> anyhow::Result<S> {
+        Ok(match transition {
+            Transition::Stay => current,
+            Transition::Goto(next) if current.can_transition(&next) => next,
+            Transition::Goto(next) => {
+                return Err(anyhow::anyhow!(
+                    "invalid transition {:?} -> {:?}",
+                    current,
+                    next
+                ))
+            }
+            Transition::Finish => S::default(),
+        })
+    }
+
+    pub async fn handle(&self, bot: Bot, msg: Message) -> anyhow::Result<()> {
+        let chat_id = msg.chat.id;
+
+        let stored = MutDialogueState::try_for_chat(
+            chat_id,
+            Dialogue::new(self.storage.clone(), chat_id),
+            self.lock_timeout,
+        )
+        .await?;
+
+        let now = unix_now();
+        let current = self.resume(&stored.get(), now);
+
+        let next = if is_cancel(msg.text()) {
+            if let Some(reply) = &self.cancel_reply {
+                bot.send_message(chat_id, reply).await?;
+            }
+
+            S::fallback()
+        } else {
+            let Some((_, handler)) = self.handlers.iter().find(|(matches, _)| matches(&current))
+            else {
+                return Err(anyhow::anyhow!("no handler for state {:?}", current));
+            };
+
+            // an error leaves the stored state as it was
+            let transition = handler(bot, msg, current.clone()).await?;
+            Self::apply(current, transition)?
+        };
+
+        *stored.as_mut() = MachineState {
+            state: next,
+            last_activity_unix: now,
+        };
+
+        stored.commit().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use teloxide::{dispatching::dialogue::InMemStorage, types::ChatId};
+
+    use super::*;
+    use crate::teloxide::{
+        dialogue_state::{LockTimeout, EXCLUSIONS},
+        fake_api,
+    };
+
+    #[derive(Debug, Clone, Default, PartialEq)]
+    enum Order {
+        #[default]
+        Idle,
+        AskSize,
+        AskAddress(u32),
+    }
+
+    impl DialogueStates for Order {
+        fn can_transition(&self, next: &Self) -> bool {
+            !matches!((self, next), (Order::Idle, Order::AskAddress(_)))
+        }
+    }
+
+    #[test]
+    fn validates_and_times_out() {
+        let machine = DialogueMachine::<Order, _>::new(InMemStorage::new())
+            .inactivity_timeout(Duration::from_secs(60));
+
+        assert!(DialogueMachine::<Order, InMemStorage<_>>::apply(
+            Order::Idle,
+            Transition::Goto(Order::AskAddress(1))
+        )
+        .is_err());
+        assert_eq!(
+            DialogueMachine::<Order, InMemStorage<_>>::apply(
+                Order::AskSize,
+                Transition::Goto(Order::AskAddress(1))
+            )
+            .unwrap(),
+            Order::AskAddress(1)
+        );
+
+        let stored = MachineState {
+            state: Order::AskSize,
+            last_activity_unix: 1000,
+        };
+        assert_eq!(machine.resume(&stored, 1030), Order::AskSize);
+        assert_eq!(machine.resume(&stored, 1100), Order::Idle);
+
+        assert!(is_cancel(Some("/cancel@my_bot")));
+        assert!(!is_cancel(Some("/cancellation")));
+    }
+
+    #[tokio::test]
+    async fn handle_waits_for_the_chat_lock() -> anyhow::Result<()> {
+        let storage = InMemStorage::new();
+        let machine = DialogueMachine::<Order, _>::new(storage.clone())
+            .lock_timeout(Duration::from_millis(50))
+            .on(
+                |state| *state == Order::Idle,
+                |_, _, _| async { Ok(Transition::Goto(Order::AskSize)) },
+            );
+
+        let chat_id = ChatId(6);
+        let mut msg: Message = serde_json::from_value(fake_api::message(1, "hi")["result"].take())?;
+        msg.chat.id = chat_id;
+        let bot = Bot::new("test");
+
+        let holder =
+            MutDialogueState::for_chat(chat_id, Dialogue::new(storage.clone(), chat_id)).await;
+        let Err(e) = machine.handle(bot.clone(), msg.clone()).await else {
+            panic!("should time out");
+        };
+        assert!(e.downcast_ref::<LockTimeout>().is_some());
+        assert!(EXCLUSIONS.contains_key(&chat_id));
+
+        // the last one out removes the chat's entry
+        drop(holder);
+        assert!(!EXCLUSIONS.contains_key(&chat_id));
+
+        machine.handle(bot, msg).await?;
+        assert!(!EXCLUSIONS.contains_key(&chat_id));
+        assert_eq!(
+            Dialogue::new(storage, chat_id)
+                .get()
+                .await?
+                .map(|stored| stored.state),
+            Some(Order::AskSize)
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,315 @@
+use std::{
+    fmt::Display,
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use teloxide::{dispatching::dialogue::Storage, types::ChatId};
+
+// `{"__state_version": 2, "state": ...}`; anything else is a version 0 row from before the envelope
+const VERSION_KEY: &str = "__state_version";
+const STATE_KEY: &str = "state";
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+pub type Migration = Box<dyn Fn(Value) -> anyhow::Result<Value> + Send + Sync>;
+
+#[derive(Default)]
+pub struct Migrations {
+    steps: Vec<Migration>,
+}
+
+impl Migrations {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // from `self.version()` to the one after it
+    pub fn step(
+        mut self,
+        f: impl Fn(Value) -> anyhow::Result<Value> + Send + Sync + 'static,
+    ) -> Self {
+        self.steps.push(Box::new(f));
+        self
+    }
+
+    pub fn version(&self) -> u32 {
+        self.steps.len() as u32
+    }
+
+    pub fn migrate(&self, from: u32, mut state: Value) -> anyhow::Result<Value> {
+        if from > self.version() {
+            return Err(anyhow::anyhow!(
+                "state version {} is newer than {}",
+                from,
+                self.version()
+            ));
+        }
+
+        for (version, step) in self.steps.iter().enumerate().skip(from as usize) {
+            state = step(state)
+                .map_err(|e| anyhow::anyhow!("migrating from version {}: {}", version, e))?;
+        }
+
+        Ok(state)
+    }
+}
+
+fn open_envelope(stored: Value) -> (u32, Value) {
+    match stored {
+        Value::Object(mut map) if map.len() == 2 && map.contains_key(STATE_KEY) => {
+            match map.get(VERSION_KEY).and_then(Value::as_u64) {
+                Some(version) => (version as u32, map.remove(STATE_KEY).unwrap()),
+                None => (0, Value::Object(map)),
+            }
+        }
+        other => (0, other),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QuarantinedState {
+    pub at_unix: u64,
+    pub version: u32,
+    pub state: Value,
+    pub error: String,
+}
+
+// wraps a storage of raw JSON values, so it needs a self-describing serializer (`Json`, `Cbor`)
+pub struct MigratingStorage<S: ?Sized> {
+    inner: Arc<S>,
+    quarantine: Option<Arc<S>>,
+    migrations: Migrations,
+}
+
+impl<S> MigratingStorage<S>
+where
+    S: Storage<Value> + ?Sized + Send + Sync + 'static,
+    S::Error: Display,
+{
+    pub fn new(inner: Arc<S>, migrations: Migrations) -> Self {
+        Self {
+            inner,
+            quarantine: None,
+            migrations,
+        }
+    }
+
+    // state that can't be migrated goes here instead of failing every update of its chat
+    pub fn quarantine(mut self, quarantine: Arc<S>) -> Self {
+        self.quarantine = Some(quarantine);
+        self
+    }
+
+    pub async fn quarantined(&self, chat_id: ChatId) -> anyhow::Result<Vec<QuarantinedState>> {
+        let Some(quarantine) = &self.quarantine else {
+            return Ok(vec![]);
+        };
+
+        let stored = quarantine
+            .clone()
+            .get_dialogue(chat_id)
+            .await
+            .map_err(|e| anyhow::anyhow!("{}", e))?;
+
+        Ok(match stored {
+            Some(stored) => serde_json::from_value(stored)?,
+            None => vec![],
+        })
+    }
+
+    async fn put_in_quarantine(
+        &self,
+        chat_id: ChatId,
+        version: u32,
+        state: Value,
+        error: anyhow::Error,
+    ) -> anyhow::Result<()> {
+        let Some(quarantine) = &self.quarantine else {
+            return Err(error);
+        };
+
+        eprintln!("Quarantining state of chat {}: {}", chat_id, error);
+
+        let mut quarantined = self.quarantined(chat_id).await?;
+        quarantined.push(QuarantinedState {
+            at_unix: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            version,
+            state,
+            error: error.to_string(),
+        });
+
+        quarantine
+            .clone()
+            .update_dialogue(chat_id, serde_json::to_value(quarantined)?)
+            .await
+            .map_err(|e| anyhow::anyhow!("{}", e))?;
+
+        self.inner
+            .clone()
+            .remove_dialogue(chat_id)
+            .await
+            .map_err(|e| anyhow::anyhow!("{}", e))
+    }
+}
+
+impl<S, D> Storage<D> for MigratingStorage<S>
+where
+    S: Storage<Value> + ?Sized + Send + Sync + 'static,
+    S::Error: Display,
+    D: Serialize + DeserializeOwned + Send + 'static,
+{
+    type Error = anyhow::Error;
+
+    fn remove_dialogue(self: Arc<Self>, chat_id: ChatId) -> BoxFuture<Result<(), Self::Error>>
+    where
+        D: Send + 'static,
+    {
+        Box::pin(async move {
+            self.inner
+                .clone()
+                .remove_dialogue(chat_id)
+                .await
+                .map_err(|e| anyhow::anyhow!("{}", e))
+        })
+    }
+
+    fn update_dialogue(
+        self: Arc<Self>,
+        chat_id: ChatId,
+        dialogue: D,
+    ) -> BoxFuture<Result<(), Self::Error>>
+    where
+        D: Send + 'static,
+    {
+        Box::pin(async move {
+            let envelope = json!({
+                VERSION_KEY: self.migrations.version(),
+                STATE_KEY: serde_json::to_value(dialogue)?,
+            });
+
+            self.inner
+                .clone()
+                .update_dialogue(chat_id, envelope)
+                .await
+                .map_err(|e| anyhow::anyhow!("{}", e))
+        })
+    }
+
+    fn get_dialogue(self: Arc<Self>, chat_id: ChatId) -> BoxFuture<Result<Option<D>, Self::Error>> {
+        Box::pin(async move {
+            let stored = self
+                .inner
+                .clone()
+                .get_dialogue(chat_id)
+                .await
+                .map_err(|e| anyhow::anyhow!("{}", e))?;
+
+            let Some(stored) = stored else {
+                return Ok(None);
+            };
+
+            let (version, state) = open_envelope(stored);
+
+            if version > self.migrations.version() {
+                // written by a newer build; not ours to quarantine
+                return Err(anyhow::anyhow!(
+                    "state of chat {} has version {}, newer than {}",
+                    chat_id,
+                    version,
+                    self.migrations.version()
+                ));
+            }
+
+            let migrated = self
+                .migrations
+                .migrate(version, state.clone())
+                .and_then(|migrated| Ok(serde_json::from_value(migrated)?));
+
+            match migrated {
+                Ok(dialogue) => Ok(Some(dialogue)),
+                Err(e) => {
+                    self.put_in_quarantine(chat_id, version, state, e).await?;
+                    Ok(None)
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use teloxide::dispatching::dialogue::InMemStorage;
+
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct State {
+        name: String,
+        visits: u32,
+    }
+
+    #[tokio::test]
+    async fn migrates_and_quarantines() -> anyhow::Result<()> {
+        let inner = InMemStorage::<Value>::new();
+        let quarantine = InMemStorage::<Value>::new();
+
+        // version 0 was a bare name, version 1 had `count`
+        let storage = Arc::new(
+            MigratingStorage::new(
+                inner.clone(),
+                Migrations::new()
+                    .step(|name| Ok(json!({ "name": name, "count": 0 })))
+                    .step(|mut state| {
+                        state["visits"] = state["count"].take();
+                        Ok(state)
+                    }),
+            )
+            .quarantine(quarantine),
+        );
+
+        inner
+            .clone()
+            .update_dialogue(ChatId(1), json!("ann"))
+            .await?;
+        inner
+            .clone()
+            .update_dialogue(ChatId(2), json!([1, 2]))
+            .await?;
+
+        let state: Option<State> = storage.clone().get_dialogue(ChatId(1)).await?;
+        assert_eq!(
+            state,
+            Some(State {
+                name: "ann".to_owned(),
+                visits: 0
+            })
+        );
+
+        storage
+            .clone()
+            .update_dialogue(ChatId(1), state.unwrap())
+            .await?;
+        assert_eq!(
+            inner.clone().get_dialogue(ChatId(1)).await?.unwrap()[VERSION_KEY],
+            2
+        );
+
+        let broken: Option<State> = storage.clone().get_dialogue(ChatId(2)).await?;
+        assert_eq!(broken, None);
+        assert_eq!(inner.clone().get_dialogue(ChatId(2)).await?, None);
+        assert_eq!(
+            storage.quarantined(ChatId(2)).await?[0].state,
+            json!([1, 2])
+        );
+
+        Ok(())
+    }
+}
@@ -1,7 +1,12 @@
 use std::{
+    fmt::Display,
     mem::ManuallyDrop,
     ops::{Deref, DerefMut},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Weak,
+    },
+    time::Duration,
 };
 
 use async_lock::{Semaphore, SemaphoreGuardArc};
@@ -15,57 +20,228 @@ use teloxide::{
     prelude::Dialogue,
     types::{ChatId, Update},
 };
+use tokio::{sync::watch, task::JoinHandle};
 
-static EXCLUSIONS: Lazy<DashMap<ChatId, Arc<async_lock::Semaphore>>> = Lazy::new(DashMap::new);
+// weak, so chats nobody is waiting on don't keep their semaphore alive
+pub(super) static EXCLUSIONS: Lazy<DashMap<ChatId, Weak<Semaphore>>> = Lazy::new(DashMap::new);
 
-type TeloDialogue<T> = Dialogue<T, SqliteStorage<Json>>;
+pub const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(30);
 
-#[derive(Clone)]
-pub struct MutDialogueState<T>
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LockTimeout {
+    pub chat_id: ChatId,
+    pub waited: Duration,
+}
+
+impl Display for LockTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "timed out after {:?} waiting for the dialogue lock of chat {}",
+            self.waited, self.chat_id
+        )
+    }
+}
+
+impl std::error::Error for LockTimeout {}
+
+// chats currently holding or waiting for their lock
+pub fn locked_chats() -> usize {
+    EXCLUSIONS.len()
+}
+
+// saves started from `Drop` that haven't finished yet
+static PENDING_SAVES: Lazy<watch::Sender<usize>> = Lazy::new(|| watch::channel(0).0);
+
+pub fn pending_saves() -> usize {
+    *PENDING_SAVES.borrow()
+}
+
+// call on shutdown, after the dispatcher stops, so the last updates' state isn't lost
+pub async fn flush_pending_saves(timeout: Duration) -> anyhow::Result<()> {
+    let mut pending = PENDING_SAVES.subscribe();
+
+    tokio::time::timeout(timeout, pending.wait_for(|pending| *pending == 0))
+        .await
+        .map_err(|_| {
+            anyhow::anyhow!(
+                "{} dialogue states still unsaved after {:?}",
+                pending_saves(),
+                timeout
+            )
+        })??;
+
+    Ok(())
+}
+
+// held by waiters as well as holders: whoever lets go of the semaphore last, even by timing
+// out or being cancelled, removes the chat's entry
+struct ChatSemaphore {
+    chat_id: ChatId,
+    semaphore: Option<Arc<Semaphore>>,
+}
+
+impl ChatSemaphore {
+    fn new(chat_id: ChatId) -> Self {
+        let mut entry = EXCLUSIONS.entry(chat_id).or_default();
+
+        let semaphore = entry.upgrade().unwrap_or_else(|| {
+            let semaphore = Arc::new(Semaphore::new(1));
+            *entry = Arc::downgrade(&semaphore);
+            semaphore
+        });
+
+        Self {
+            chat_id,
+            semaphore: Some(semaphore),
+        }
+    }
+
+    fn get(&self) -> &Arc<Semaphore> {
+        self.semaphore.as_ref().expect("only taken on drop")
+    }
+}
+
+impl Drop for ChatSemaphore {
+    fn drop(&mut self) {
+        drop(self.semaphore.take());
+
+        // under the shard lock, so nobody can pick the semaphore up in between
+        EXCLUSIONS.remove_if(&self.chat_id, |_, semaphore| semaphore.strong_count() == 0);
+    }
+}
+
+struct ChatLock {
+    // reports the lock being held for longer than others wait for it
+    watchdog: JoinHandle<()>,
+    // fields drop in order, so the permit's reference is gone before the cleanup
+    _guard: SemaphoreGuardArc,
+    _semaphore: ChatSemaphore,
+}
+
+impl ChatLock {
+    async fn acquire(chat_id: ChatId, timeout: Duration) -> Result<Self, LockTimeout> {
+        let semaphore = ChatSemaphore::new(chat_id);
+
+        let guard = tokio::time::timeout(timeout, semaphore.get().acquire_arc())
+            .await
+            .map_err(|_| LockTimeout {
+                chat_id,
+                waited: timeout,
+            })?;
+
+        let watchdog = tokio::spawn(async move {
+            let started = tokio::time::Instant::now();
+
+            loop {
+                tokio::time::sleep(timeout).await;
+                println!(
+                    "chat {} has held its dialogue lock for {:?}, others time out waiting",
+                    chat_id,
+                    started.elapsed()
+                );
+            }
+        });
+
+        Ok(Self {
+            watchdog,
+            _guard: guard,
+            _semaphore: semaphore,
+        })
+    }
+}
+
+impl Drop for ChatLock {
+    fn drop(&mut self) {
+        self.watchdog.abort();
+    }
+}
+
+type TeloDialogue<T, S> = Dialogue<T, S>;
+
+pub struct MutDialogueState<T, S = SqliteStorage<Json>>
 where
     T: Send + Sync + Clone + 'static + Default + Debug,
-    SqliteStorage<Json>: Storage<T>,
-    <SqliteStorage<Json> as Storage<T>>::Error: std::fmt::Display,
+    S: Storage<T> + ?Sized + Send + Sync + 'static,
+    S::Error: std::fmt::Display,
 {
     chat_id: ChatId,
-    telodial: TeloDialogue<T>,
-    state: ManuallyDrop<Arc<(SemaphoreGuardArc, Mutex<T>)>>,
+    telodial: TeloDialogue<T, S>,
+    lock_wait: Duration,
+    // the flag is set by `as_mut`, so state that was only read isn't written back
+    state: ManuallyDrop<Arc<(ChatLock, Mutex<T>, AtomicBool)>>,
+}
+
+// not derived: that would require `S: Clone`, and storages are shared through an `Arc` anyway
+impl<T, S> Clone for MutDialogueState<T, S>
+where
+    T: Send + Sync + Clone + 'static + Default + Debug,
+    S: Storage<T> + ?Sized + Send + Sync + 'static,
+    S::Error: std::fmt::Display,
+{
+    fn clone(&self) -> Self {
+        Self {
+            chat_id: self.chat_id,
+            telodial: self.telodial.clone(),
+            lock_wait: self.lock_wait,
+            state: self.state.clone(),
+        }
+    }
 }
 
-impl<T> MutDialogueState<T>
+impl<T, S> MutDialogueState<T, S>
 where
     T: Send + Sync + Clone + 'static + Default + Debug,
-    SqliteStorage<Json>: Storage<T>,
-    <SqliteStorage<Json> as Storage<T>>::Error: std::fmt::Display,
+    S: Storage<T> + ?Sized + Send + Sync + 'static,
+    S::Error: std::fmt::Display,
 {
-    pub async fn new(update: Update, telodial: TeloDialogue<T>) -> Option<Self> {
-        let chat_id = update.chat_id()?;
+    pub async fn new(update: Update, telodial: TeloDialogue<T, S>) -> Option<Self> {
+        Self::try_new(update, telodial)
+            .await
+            .inspect_err(|e| {
+                eprintln!("Error getting state: {}", e);
+            })
+            .ok()
+    }
 
-        let excl = EXCLUSIONS
-            .entry(chat_id)
-            .or_insert_with(|| Arc::new(Semaphore::new(1)))
-            // .clone() // doesn't deadlock?
-            ;
+    pub async fn for_chat(chat_id: ChatId, telodial: TeloDialogue<T, S>) -> Option<Self> {
+        Self::try_for_chat(chat_id, telodial, DEFAULT_LOCK_TIMEOUT)
+            .await
+            .inspect_err(|e| {
+                eprintln!("Error getting state: {}", e);
+            })
+            .ok()
+    }
 
-        // println!("Will wait...");
+    pub async fn try_new(update: Update, telodial: TeloDialogue<T, S>) -> anyhow::Result<Self> {
+        let chat_id = update
+            .chat_id()
+            .ok_or_else(|| anyhow::anyhow!("update has no chat"))?;
 
-        let guard = excl.acquire_arc().await;
+        Self::try_for_chat(chat_id, telodial, DEFAULT_LOCK_TIMEOUT).await
+    }
 
-        // println!("Got...");
+    // a `LockTimeout` error if another update of the chat holds the state for longer than `lock_timeout`
+    pub async fn try_for_chat(
+        chat_id: ChatId,
+        telodial: TeloDialogue<T, S>,
+        lock_timeout: Duration,
+    ) -> anyhow::Result<Self> {
+        let started = tokio::time::Instant::now();
+        let lock = ChatLock::acquire(chat_id, lock_timeout).await?;
+        let lock_wait = started.elapsed();
 
         let state = telodial
             .get()
             .await
-            .inspect_err(|e| {
-                eprintln!("Error getting state: {}", e);
-            })
-            .ok()?
+            .map_err(|e| anyhow::anyhow!("{}", e))?
             .unwrap_or_default();
 
-        Some(Self {
+        Ok(Self {
             chat_id,
             telodial,
-            state: ManuallyDrop::new(Arc::new((guard, Mutex::new(state)))),
+            lock_wait,
+            state: ManuallyDrop::new(Arc::new((lock, Mutex::new(state), AtomicBool::new(false)))),
         })
     }
 
@@ -73,41 +249,189 @@ where
         self.chat_id
     }
 
+    // how long this update waited for the previous one of the same chat
+    pub fn lock_wait(&self) -> Duration {
+        self.lock_wait
+    }
+
     pub fn get(&self) -> impl Deref<Target = T> + Debug + Send + Sync + '_ {
         self.state.1.lock()
     }
 
     pub fn as_mut(&self) -> impl DerefMut<Target = T> + Send + Sync + '_ {
+        self.state.2.store(true, Ordering::SeqCst);
         self.state.1.lock()
     }
+
+    pub fn is_dirty(&self) -> bool {
+        self.state.2.load(Ordering::SeqCst)
+    }
+
+    // saves now instead of in the background on drop; clones still alive save their later changes themselves
+    pub async fn commit(self) -> anyhow::Result<()> {
+        if !self.state.2.swap(false, Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        let state = self.state.1.lock().clone();
+
+        if let Err(e) = self.telodial.update(state).await {
+            self.state.2.store(true, Ordering::SeqCst);
+            return Err(anyhow::anyhow!("{}", e));
+        }
+
+        Ok(())
+    }
 }
 
-impl<T> Drop for MutDialogueState<T>
+impl<T, S> Drop for MutDialogueState<T, S>
 where
     T: Send + Sync + Clone + 'static + Default + Debug,
-    SqliteStorage<Json>: Storage<T>,
-    <SqliteStorage<Json> as Storage<T>>::Error: std::fmt::Display,
+    S: Storage<T> + ?Sized + Send + Sync + 'static,
+    S::Error: std::fmt::Display,
 {
     fn drop(&mut self) {
         let arc = unsafe { ManuallyDrop::take(&mut self.state) };
-        let Some((guard, mutex)) = Arc::into_inner(arc) else {
+        let Some((guard, mutex, dirty)) = Arc::into_inner(arc) else {
             // println!("Not dropping MutDialogueState for chat {}", self.chat_id);
             return;
         };
+
+        if !dirty.into_inner() {
+            return;
+        }
+
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            eprintln!(
+                "Error setting state: no runtime to save chat {} with",
+                self.chat_id
+            );
+            return;
+        };
+
         let state = mutex.into_inner();
         let telodial = self.telodial.clone();
 
-        tokio::spawn(async move {
-            // println!("Saving state...");
-
-            // tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
+        PENDING_SAVES.send_modify(|pending| *pending += 1);
 
+        runtime.spawn(async move {
             let _ = telodial.update(state).await.inspect_err(|e| {
                 eprintln!("Error setting state: {}", e);
             });
 
             drop(guard);
-            // println!("Released...");
+            PENDING_SAVES.send_modify(|pending| *pending -= 1);
         });
     }
 }
+
+#[cfg(test)]
+mod test {
+    use teloxide::dispatching::dialogue::InMemStorage;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn evicts_and_times_out() -> anyhow::Result<()> {
+        let storage = InMemStorage::<u32>::new();
+        let chat_id = ChatId(2);
+
+        let state =
+            MutDialogueState::for_chat(chat_id, Dialogue::new(storage.clone(), chat_id)).await;
+        assert!(EXCLUSIONS.contains_key(&chat_id));
+
+        let Err(e) = MutDialogueState::try_for_chat(
+            chat_id,
+            Dialogue::new(storage.clone(), chat_id),
+            Duration::from_millis(50),
+        )
+        .await
+        else {
+            panic!("should time out");
+        };
+        assert!(e.downcast_ref::<LockTimeout>().is_some());
+
+        drop(state);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!EXCLUSIONS.contains_key(&chat_id));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cleans_up_after_waiters() -> anyhow::Result<()> {
+        let chat_id = ChatId(4);
+
+        // times out while the holder still has the lock
+        let holder = ChatLock::acquire(chat_id, DEFAULT_LOCK_TIMEOUT).await?;
+        assert!(ChatLock::acquire(chat_id, Duration::from_millis(20))
+            .await
+            .is_err());
+        drop(holder);
+        assert!(!EXCLUSIONS.contains_key(&chat_id));
+
+        // cancelled after the holder let go, but before it got the lock
+        let holder = ChatLock::acquire(chat_id, DEFAULT_LOCK_TIMEOUT).await?;
+        let mut waiter = Box::pin(ChatLock::acquire(chat_id, DEFAULT_LOCK_TIMEOUT));
+        tokio::select! {
+            biased;
+            _ = &mut waiter => panic!("the lock is held"),
+            _ = async {} => {}
+        }
+        drop(holder);
+        assert!(EXCLUSIONS.contains_key(&chat_id));
+        drop(waiter);
+        assert!(!EXCLUSIONS.contains_key(&chat_id));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn commits_only_changes() -> anyhow::Result<()> {
+        let storage = InMemStorage::<u32>::new();
+        let chat_id = ChatId(3);
+        let dialogue = Dialogue::new(storage.clone(), chat_id);
+
+        let state =
+            MutDialogueState::try_for_chat(chat_id, dialogue.clone(), DEFAULT_LOCK_TIMEOUT).await?;
+        assert_eq!(*state.get(), 0);
+        assert!(!state.is_dirty());
+        drop(state);
+        assert_eq!(dialogue.get().await?, None);
+
+        let state =
+            MutDialogueState::try_for_chat(chat_id, dialogue.clone(), DEFAULT_LOCK_TIMEOUT).await?;
+        *state.as_mut() = 7;
+        state.commit().await?;
+        assert_eq!(dialogue.get().await?, Some(7));
+
+        let state =
+            MutDialogueState::try_for_chat(chat_id, dialogue.clone(), DEFAULT_LOCK_TIMEOUT).await?;
+        *state.as_mut() = 8;
+        drop(state);
+        flush_pending_saves(Duration::from_secs(1)).await?;
+        assert_eq!(dialogue.get().await?, Some(8));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn works_with_any_storage() {
+        let storage = InMemStorage::<u32>::new();
+        let chat_id = ChatId(1);
+
+        let state = MutDialogueState::for_chat(chat_id, Dialogue::new(storage.clone(), chat_id))
+            .await
+            .unwrap();
+        *state.as_mut() += 41;
+        let copy = state.clone();
+        *copy.as_mut() += 1;
+        drop((state, copy));
+
+        // the next one waits for the previous save
+        let state = MutDialogueState::for_chat(chat_id, Dialogue::new(storage, chat_id))
+            .await
+            .unwrap();
+        assert_eq!(*state.get(), 42);
+    }
+}
@@ -0,0 +1,201 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+    time::Duration,
+};
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use teloxide::types::Recipient;
+use tokio::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rate {
+    pub count: usize,
+    pub per: Duration,
+}
+
+impl Rate {
+    pub const fn new(count: usize, per: Duration) -> Self {
+        Self { count, per }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EditLimits {
+    // between two edits of the same message; whatever comes in meanwhile is merged into one edit
+    pub throttle: Duration,
+    pub per_chat: Rate,
+    pub global: Rate,
+}
+
+// what Telegram asks for: about one message a second per chat and 30 a second overall
+impl Default for EditLimits {
+    fn default() -> Self {
+        Self {
+            throttle: Duration::from_millis(200),
+            per_chat: Rate::new(1, Duration::from_secs(1)),
+            global: Rate::new(30, Duration::from_secs(1)),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Windows {
+    global: VecDeque<Instant>,
+    chats: HashMap<Recipient, VecDeque<Instant>>,
+    // until when Telegram told us to back off
+    paused: HashMap<Recipient, Instant>,
+}
+
+fn prune(window: &mut VecDeque<Instant>, rate: Rate, now: Instant) {
+    while window.front().is_some_and(|&sent| sent + rate.per <= now) {
+        window.pop_front();
+    }
+}
+
+fn free_at(window: &VecDeque<Instant>, rate: Rate) -> Option<Instant> {
+    let count = rate.count.max(1);
+
+    if window.len() < count {
+        return None;
+    }
+
+    Some(window[window.len() - count] + rate.per)
+}
+
+impl Windows {
+    fn prune(&mut self, limits: &EditLimits, now: Instant) {
+        prune(&mut self.global, limits.global, now);
+
+        self.chats.retain(|_, window| {
+            prune(window, limits.per_chat, now);
+            !window.is_empty()
+        });
+
+        self.paused.retain(|_, until| *until > now);
+    }
+
+    fn free_at(&self, chat: &Recipient, limits: &EditLimits) -> Option<Instant> {
+        [
+            free_at(&self.global, limits.global),
+            self.chats
+                .get(chat)
+                .and_then(|window| free_at(window, limits.per_chat)),
+            self.paused.get(chat).copied(),
+        ]
+        .into_iter()
+        .flatten()
+        .max()
+    }
+}
+
+// one per bot, shared by every ProgressMessage and UpdateableMessage that talks through it
+#[derive(Debug, Default)]
+pub struct EditScheduler {
+    limits: Mutex<EditLimits>,
+    windows: Mutex<Windows>,
+}
+
+static GLOBAL: Lazy<Arc<EditScheduler>> = Lazy::new(Default::default);
+
+impl EditScheduler {
+    pub fn new(limits: EditLimits) -> Self {
+        Self {
+            limits: Mutex::new(limits),
+            windows: Mutex::default(),
+        }
+    }
+
+    pub fn global() -> Arc<Self> {
+        GLOBAL.clone()
+    }
+
+    pub fn limits(&self) -> EditLimits {
+        *self.limits.lock()
+    }
+
+    pub fn set_limits(&self, limits: EditLimits) {
+        *self.limits.lock() = limits;
+    }
+
+    // e.g. after a RetryAfter
+    pub fn pause(&self, chat: &Recipient, duration: Duration) {
+        let until = Instant::now() + duration;
+        let mut windows = self.windows.lock();
+        let paused = windows.paused.entry(chat.clone()).or_insert(until);
+        *paused = (*paused).max(until);
+    }
+
+    async fn wait_until_free(&self, chat: &Recipient, take: bool) {
+        loop {
+            let limits = self.limits();
+            let now = Instant::now();
+
+            let free_at = {
+                let mut windows = self.windows.lock();
+                windows.prune(&limits, now);
+
+                let free_at = windows.free_at(chat, &limits);
+
+                if free_at.is_none() && take {
+                    windows.global.push_back(now);
+                    windows
+                        .chats
+                        .entry(chat.clone())
+                        .or_default()
+                        .push_back(now);
+                }
+
+                free_at
+            };
+
+            match free_at {
+                Some(free_at) => tokio::time::sleep_until(free_at).await,
+                None => return,
+            }
+        }
+    }
+
+    // waits until a request to `chat` fits both budgets, then counts it
+    pub async fn acquire(&self, chat: &Recipient) {
+        self.wait_until_free(chat, true).await
+    }
+
+    // waits without counting anything, for picking up the latest text only once it can be sent
+    pub async fn ready(&self, chat: &Recipient) {
+        self.wait_until_free(chat, false).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use teloxide::types::ChatId;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn enforces_chat_and_global_budgets() {
+        let scheduler = EditScheduler::new(EditLimits {
+            throttle: Duration::ZERO,
+            per_chat: Rate::new(2, Duration::from_millis(300)),
+            global: Rate::new(3, Duration::from_millis(300)),
+        });
+        let (a, b) = (Recipient::Id(ChatId(1)), Recipient::Id(ChatId(2)));
+        let started = Instant::now();
+
+        scheduler.acquire(&a).await;
+        scheduler.acquire(&a).await;
+        scheduler.acquire(&b).await;
+        assert!(started.elapsed() < Duration::from_millis(100));
+
+        // `b` has room of its own, but not globally
+        scheduler.acquire(&b).await;
+        assert!(started.elapsed() >= Duration::from_millis(300));
+
+        scheduler.pause(&a, Duration::from_millis(200));
+        let paused = Instant::now();
+        scheduler.ready(&a).await;
+        assert!(paused.elapsed() >= Duration::from_millis(200));
+    }
+}
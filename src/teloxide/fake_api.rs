@@ -0,0 +1,117 @@
+// a stand-in for the Bot API, so tests can see the requests and script the answers
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+use teloxide::Bot;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+pub type Requests = Arc<Mutex<Vec<(String, Value)>>>;
+
+pub fn message(message_id: i32, text: &str) -> Value {
+    json!({
+        "ok": true,
+        "result": {
+            "message_id": message_id,
+            "date": 0,
+            "chat": { "id": 1, "type": "private", "first_name": "test" },
+            "text": text,
+        },
+    })
+}
+
+pub fn ok() -> Value {
+    json!({ "ok": true, "result": true })
+}
+
+pub fn error(code: u16, description: &str) -> Value {
+    json!({ "ok": false, "error_code": code, "description": description })
+}
+
+pub fn retry_after(seconds: u64) -> Value {
+    json!({
+        "ok": false,
+        "error_code": 429,
+        "description": "Too Many Requests",
+        "parameters": { "retry_after": seconds },
+    })
+}
+
+// `respond` gets the method name and the JSON payload
+pub async fn serve(
+    respond: impl Fn(&str, &Value) -> Value + Send + Sync + 'static,
+) -> anyhow::Result<(Bot, Requests)> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let url = format!("http://{}/", listener.local_addr()?);
+    let requests = Requests::default();
+    let respond = Arc::new(respond);
+
+    tokio::spawn({
+        let requests = requests.clone();
+
+        async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(connection(stream, requests.clone(), respond.clone()));
+            }
+        }
+    });
+
+    Ok((Bot::new("test").set_api_url(url.parse()?), requests))
+}
+
+async fn connection(
+    stream: TcpStream,
+    requests: Requests,
+    respond: Arc<impl Fn(&str, &Value) -> Value>,
+) -> anyhow::Result<()> {
+    let mut stream = BufReader::new(stream);
+
+    loop {
+        let mut request_line = String::new();
+        if stream.read_line(&mut request_line).await? == 0 {
+            return Ok(());
+        }
+
+        let mut content_length = 0;
+        loop {
+            let mut header = String::new();
+            stream.read_line(&mut header).await?;
+
+            if header.trim().is_empty() {
+                break;
+            }
+
+            if let Some(length) = header.to_ascii_lowercase().strip_prefix("content-length:") {
+                content_length = length.trim().parse()?;
+            }
+        }
+
+        let mut body = vec![0; content_length];
+        stream.read_exact(&mut body).await?;
+
+        let method = request_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|path| path.rsplit('/').next())
+            .unwrap_or_default()
+            .to_owned();
+        let payload: Value = serde_json::from_slice(&body).unwrap_or_default();
+
+        let response = respond(&method, &payload).to_string();
+        requests.lock().push((method, payload));
+
+        stream
+            .write_all(
+                format!(
+                    "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\r\n{}",
+                    response.len(),
+                    response
+                )
+                .as_bytes(),
+            )
+            .await?;
+    }
+}
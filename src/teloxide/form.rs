@@ -0,0 +1,628 @@
+use std::{collections::BTreeMap, fmt::Display};
+
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use teloxide::{
+    dispatching::dialogue::Storage,
+    payloads::{AnswerCallbackQuerySetters, SendMessageSetters},
+    requests::Requester,
+    types::{CallbackQuery, ChatId, InlineKeyboardButton, InlineKeyboardMarkup, Message},
+    Bot,
+};
+
+use super::{dialogue_state::MutDialogueState, updateable_message::UpdateableMessage};
+
+const CALLBACK_PREFIX: &str = "form";
+const DATE_FORMAT: &str = "%Y-%m-%d";
+// leaves room for the prefix and the longest action within Telegram's 64 bytes of callback data
+const MAX_ID_LEN: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum FieldValue {
+    Text(String),
+    Number(f64),
+    // stored as `YYYY-MM-DD` whatever format it was typed in
+    Date(String),
+    Choice(String),
+}
+
+impl FieldValue {
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            FieldValue::Text(s) | FieldValue::Date(s) | FieldValue::Choice(s) => Some(s),
+            FieldValue::Number(_) => None,
+        }
+    }
+
+    pub fn as_number(&self) -> Option<f64> {
+        match self {
+            FieldValue::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_date(&self) -> Option<NaiveDate> {
+        match self {
+            FieldValue::Date(date) => NaiveDate::parse_from_str(date, DATE_FORMAT).ok(),
+            _ => None,
+        }
+    }
+}
+
+pub type Answers = BTreeMap<String, FieldValue>;
+
+pub enum FieldKind {
+    Text,
+    Number,
+    Date { format: String },
+    // (label, value)
+    Choice(Vec<(String, String)>),
+}
+
+pub type Validator = Box<dyn Fn(&FieldValue) -> Result<(), String> + Send + Sync>;
+
+pub struct Field {
+    pub key: String,
+    pub prompt: String,
+    pub kind: FieldKind,
+    pub optional: bool,
+    validator: Option<Validator>,
+}
+
+impl Field {
+    pub fn new(key: impl Into<String>, prompt: impl Into<String>, kind: FieldKind) -> Self {
+        Self {
+            key: key.into(),
+            prompt: prompt.into(),
+            kind,
+            optional: false,
+            validator: None,
+        }
+    }
+
+    pub fn text(key: impl Into<String>, prompt: impl Into<String>) -> Self {
+        Self::new(key, prompt, FieldKind::Text)
+    }
+
+    pub fn number(key: impl Into<String>, prompt: impl Into<String>) -> Self {
+        Self::new(key, prompt, FieldKind::Number)
+    }
+
+    // `format` is a chrono format string, e.g. "%d.%m.%Y"
+    pub fn date(
+        key: impl Into<String>,
+        prompt: impl Into<String>,
+        format: impl Into<String>,
+    ) -> Self {
+        Self::new(
+            key,
+            prompt,
+            FieldKind::Date {
+                format: format.into(),
+            },
+        )
+    }
+
+    pub fn choice<L: Into<String>, V: Into<String>>(
+        key: impl Into<String>,
+        prompt: impl Into<String>,
+        options: impl IntoIterator<Item = (L, V)>,
+    ) -> Self {
+        let options = options
+            .into_iter()
+            .map(|(label, value)| (label.into(), value.into()))
+            .collect();
+
+        Self::new(key, prompt, FieldKind::Choice(options))
+    }
+
+    pub fn optional(mut self) -> Self {
+        self.optional = true;
+        self
+    }
+
+    // the error is shown under the prompt and the field is asked again
+    pub fn with_validator(
+        mut self,
+        validator: impl Fn(&FieldValue) -> Result<(), String> + Send + Sync + 'static,
+    ) -> Self {
+        self.validator = Some(Box::new(validator));
+        self
+    }
+
+    fn parse(&self, text: &str) -> Result<FieldValue, String> {
+        let text = text.trim();
+
+        if text.is_empty() {
+            return Err("please send some text".to_owned());
+        }
+
+        match &self.kind {
+            FieldKind::Text => Ok(FieldValue::Text(text.to_owned())),
+            // inf and NaN would be saved as null and the progress couldn't be loaded back
+            FieldKind::Number => text
+                .replace(',', ".")
+                .parse::<f64>()
+                .ok()
+                .filter(|n| n.is_finite())
+                .map(FieldValue::Number)
+                .ok_or_else(|| "please send a number".to_owned()),
+            FieldKind::Date { format } => NaiveDate::parse_from_str(text, format)
+                .map(|date| FieldValue::Date(date.format(DATE_FORMAT).to_string()))
+                .map_err(|_| format!("please send a date like {}", today(format))),
+            FieldKind::Choice(options) => options
+                .iter()
+                .find(|(label, value)| {
+                    label.eq_ignore_ascii_case(text) || value.eq_ignore_ascii_case(text)
+                })
+                .map(|(_, value)| FieldValue::Choice(value.clone()))
+                .ok_or_else(|| "please pick one of the buttons".to_owned()),
+        }
+    }
+
+    fn choose(&self, index: usize) -> Result<FieldValue, String> {
+        match &self.kind {
+            FieldKind::Choice(options) => options
+                .get(index)
+                .map(|(_, value)| FieldValue::Choice(value.clone()))
+                .ok_or_else(|| "that option is gone".to_owned()),
+            _ => Err("please send your answer as a message".to_owned()),
+        }
+    }
+
+    fn check(&self, value: FieldValue) -> Result<FieldValue, String> {
+        if let Some(validator) = &self.validator {
+            validator(&value)?;
+        }
+
+        Ok(value)
+    }
+
+    fn display(&self, value: &FieldValue) -> String {
+        match (&self.kind, value) {
+            (FieldKind::Choice(options), FieldValue::Choice(chosen)) => options
+                .iter()
+                .find(|(_, value)| value == chosen)
+                .map_or_else(|| chosen.clone(), |(label, _)| label.clone()),
+            (FieldKind::Date { format }, value) => value
+                .as_date()
+                .map(|date| date.format(format).to_string())
+                .unwrap_or_default(),
+            (_, FieldValue::Number(n)) => n.to_string(),
+            (_, value) => value.as_str().unwrap_or_default().to_owned(),
+        }
+    }
+}
+
+fn today(format: &str) -> String {
+    chrono::Local::now().date_naive().format(format).to_string()
+}
+
+// the dialogue state of a chat filling in a form
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FormProgress {
+    pub form: Option<String>,
+    // `fields.len()` once everything is answered and it's waiting for confirmation
+    pub step: usize,
+    pub answers: Answers,
+    error: Option<String>,
+    message: Option<UpdateableMessage>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FormOutcome {
+    // not an update for this form
+    Ignored,
+    Pending,
+    Submitted(Answers),
+    Cancelled,
+}
+
+enum Input<'a> {
+    Text(&'a str),
+    Choice(usize),
+    Back,
+    Skip,
+    Cancel,
+    Confirm,
+}
+
+impl<'a> Input<'a> {
+    fn parse_text(text: &'a str) -> Self {
+        match text.split('@').next().unwrap_or_default().trim() {
+            "/back" => Input::Back,
+            "/skip" => Input::Skip,
+            "/cancel" => Input::Cancel,
+            _ => Input::Text(text),
+        }
+    }
+
+    fn parse_callback(action: &str) -> Option<Self> {
+        Some(match action {
+            "back" => Input::Back,
+            "skip" => Input::Skip,
+            "cancel" => Input::Cancel,
+            "confirm" => Input::Confirm,
+            _ => Input::Choice(action.strip_prefix("choice:")?.parse().ok()?),
+        })
+    }
+}
+
+pub struct Form {
+    pub id: String,
+    pub title: String,
+    fields: Vec<Field>,
+}
+
+impl Form {
+    // `id` goes into callback data, so it can't be longer than 32 bytes
+    pub fn new(
+        id: impl Into<String>,
+        title: impl Into<String>,
+        fields: impl IntoIterator<Item = Field>,
+    ) -> anyhow::Result<Self> {
+        let id = id.into();
+        let fields = fields.into_iter().collect::<Vec<_>>();
+
+        if id.len() > MAX_ID_LEN {
+            anyhow::bail!("form id {:?} is longer than {} bytes", id, MAX_ID_LEN);
+        }
+
+        if fields.is_empty() {
+            anyhow::bail!("form {:?} has no fields", id);
+        }
+
+        for (i, field) in fields.iter().enumerate() {
+            if fields[..i].iter().any(|f| f.key == field.key) {
+                anyhow::bail!("form {:?} has field {:?} twice", id, field.key);
+            }
+        }
+
+        Ok(Self {
+            id,
+            title: title.into(),
+            fields,
+        })
+    }
+
+    fn callback_data(&self, action: &str) -> String {
+        format!("{}:{}:{}", CALLBACK_PREFIX, self.id, action)
+    }
+
+    fn button(&self, label: &str, action: &str) -> InlineKeyboardButton {
+        InlineKeyboardButton::callback(label, self.callback_data(action))
+    }
+
+    fn summary(&self, progress: &FormProgress) -> String {
+        let mut text = self.title.clone();
+
+        for field in self.fields.iter().take(progress.step) {
+            let answer = progress
+                .answers
+                .get(&field.key)
+                .map_or_else(|| "—".to_owned(), |value| field.display(value));
+
+            text.push_str(&format!("\n{}: {}", field.key, answer));
+        }
+
+        text
+    }
+
+    fn render(&self, progress: &FormProgress) -> (String, InlineKeyboardMarkup) {
+        let mut text = self.summary(progress);
+        let mut rows = vec![];
+        let mut nav = vec![];
+
+        if progress.step > 0 {
+            nav.push(self.button("◀️ Back", "back"));
+        }
+
+        match self.fields.get(progress.step) {
+            Some(field) => {
+                text.push_str(&format!("\n\n{}", field.prompt));
+
+                if let FieldKind::Choice(options) = &field.kind {
+                    for (i, (label, _)) in options.iter().enumerate() {
+                        rows.push(vec![self.button(label, &format!("choice:{}", i))]);
+                    }
+                }
+
+                if field.optional {
+                    nav.push(self.button("⏭ Skip", "skip"));
+                }
+            }
+            None => {
+                text.push_str("\n\nAll correct?");
+                nav.push(self.button("✅ Submit", "confirm"));
+            }
+        }
+
+        if let Some(error) = &progress.error {
+            text.push_str(&format!("\n⚠️ {}", error));
+        }
+
+        nav.push(self.button("✖️ Cancel", "cancel"));
+        rows.push(nav);
+
+        (text, InlineKeyboardMarkup::new(rows))
+    }
+
+    fn advance(&self, progress: &mut FormProgress, input: Input) -> FormOutcome {
+        progress.error = None;
+        let field = self.fields.get(progress.step);
+
+        let answer = match (input, field) {
+            (Input::Cancel, _) => return FormOutcome::Cancelled,
+            (Input::Confirm, None) => return FormOutcome::Submitted(progress.answers.clone()),
+            (Input::Back, _) => {
+                progress.step = progress.step.saturating_sub(1);
+                return FormOutcome::Pending;
+            }
+            (Input::Skip, Some(field)) if field.optional => {
+                progress.answers.remove(&field.key);
+                progress.step += 1;
+                return FormOutcome::Pending;
+            }
+            (Input::Skip, Some(_)) => Err("this one can't be skipped".to_owned()),
+            (Input::Text(text), Some(field)) => field.parse(text).and_then(|v| field.check(v)),
+            (Input::Choice(i), Some(field)) => field.choose(i).and_then(|v| field.check(v)),
+            (_, _) => Err("please use the buttons".to_owned()),
+        };
+
+        match (answer, field) {
+            (Ok(value), Some(field)) => {
+                progress.answers.insert(field.key.clone(), value);
+                progress.step += 1;
+            }
+            (Err(error), _) => progress.error = Some(error),
+            (Ok(_), None) => {}
+        }
+
+        FormOutcome::Pending
+    }
+
+    async fn show(
+        &self,
+        bot: &Bot,
+        chat_id: ChatId,
+        progress: &mut FormProgress,
+    ) -> anyhow::Result<()> {
+        let (text, markup) = self.render(progress);
+
+        match &mut progress.message {
+            Some(message) => message.update(bot, &text, Some(markup)).await,
+            None => {
+                let message =
+                    UpdateableMessage::new(bot.send_message(chat_id, text).reply_markup(markup))
+                        .await?;
+                progress.message = Some(message);
+                Ok(())
+            }
+        }
+    }
+
+    async fn step<S>(
+        &self,
+        bot: &Bot,
+        chat_id: ChatId,
+        state: &MutDialogueState<FormProgress, S>,
+        input: Input<'_>,
+    ) -> anyhow::Result<FormOutcome>
+    where
+        S: Storage<FormProgress> + ?Sized + Send + Sync + 'static,
+        S::Error: Display,
+    {
+        // the state lock can't be held across the edits
+        let mut progress = state.get().clone();
+        let outcome = self.advance(&mut progress, input);
+
+        let closing = match &outcome {
+            FormOutcome::Submitted(_) => {
+                Some(format!("{}\n\n✅ Submitted", self.summary(&progress)))
+            }
+            FormOutcome::Cancelled => Some(format!("{}\n\n✖️ Cancelled", self.title)),
+            _ => None,
+        };
+
+        match closing {
+            Some(text) => {
+                if let Some(message) = &mut progress.message {
+                    message.update(bot, &text, None).await?;
+                }
+
+                *state.as_mut() = FormProgress::default();
+            }
+            None => {
+                self.show(bot, chat_id, &mut progress).await?;
+                *state.as_mut() = progress;
+            }
+        }
+
+        Ok(outcome)
+    }
+
+    // replaces whatever form the chat was filling in
+    pub async fn start<S>(
+        &self,
+        bot: &Bot,
+        chat_id: ChatId,
+        state: &MutDialogueState<FormProgress, S>,
+    ) -> anyhow::Result<()>
+    where
+        S: Storage<FormProgress> + ?Sized + Send + Sync + 'static,
+        S::Error: Display,
+    {
+        let mut progress = FormProgress {
+            form: Some(self.id.clone()),
+            ..Default::default()
+        };
+
+        self.show(bot, chat_id, &mut progress).await?;
+        *state.as_mut() = progress;
+
+        Ok(())
+    }
+
+    pub fn is_active(&self, progress: &FormProgress) -> bool {
+        progress.form.as_ref() == Some(&self.id)
+    }
+
+    pub async fn handle_message<S>(
+        &self,
+        bot: &Bot,
+        msg: &Message,
+        state: &MutDialogueState<FormProgress, S>,
+    ) -> anyhow::Result<FormOutcome>
+    where
+        S: Storage<FormProgress> + ?Sized + Send + Sync + 'static,
+        S::Error: Display,
+    {
+        let Some(text) = msg.text() else {
+            return Ok(FormOutcome::Ignored);
+        };
+
+        if !self.is_active(&state.get()) {
+            return Ok(FormOutcome::Ignored);
+        }
+
+        self.step(bot, msg.chat.id, state, Input::parse_text(text))
+            .await
+    }
+
+    pub async fn handle_callback<S>(
+        &self,
+        bot: &Bot,
+        q: &CallbackQuery,
+        state: &MutDialogueState<FormProgress, S>,
+    ) -> anyhow::Result<FormOutcome>
+    where
+        S: Storage<FormProgress> + ?Sized + Send + Sync + 'static,
+        S::Error: Display,
+    {
+        let prefix = format!("{}:{}:", CALLBACK_PREFIX, self.id);
+
+        let (Some(msg), Some(input)) = (
+            &q.message,
+            q.data
+                .as_deref()
+                .and_then(|data| data.strip_prefix(&prefix))
+                .and_then(Input::parse_callback),
+        ) else {
+            return Ok(FormOutcome::Ignored);
+        };
+
+        // buttons of a form that was cancelled, submitted or restarted since; they're on
+        // the last message when a long form is split
+        let current = {
+            let progress = state.get();
+            self.is_active(&progress)
+                && progress
+                    .message
+                    .as_ref()
+                    .is_some_and(|message| message.message_ids().last() == Some(&msg.id))
+        };
+
+        if !current {
+            bot.answer_callback_query(&q.id)
+                .text("this form is no longer active")
+                .await?;
+            return Ok(FormOutcome::Ignored);
+        }
+
+        bot.answer_callback_query(&q.id).await?;
+
+        self.step(bot, msg.chat.id, state, input).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn form() -> Form {
+        Form::new(
+            "signup",
+            "Sign up",
+            [
+                Field::text("name", "Your name?"),
+                Field::number("age", "Your age?")
+                    .optional()
+                    .with_validator(|age| match age.as_number() {
+                        Some(age) if age >= 18.0 => Ok(()),
+                        _ => Err("adults only".to_owned()),
+                    }),
+                Field::date("born", "Birthday?", "%d.%m.%Y"),
+                Field::choice("plan", "Plan?", [("Free", "free"), ("Pro", "pro")]),
+            ],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn walks_through_fields() {
+        let form = form();
+        let mut progress = FormProgress::default();
+
+        form.advance(&mut progress, Input::Text("Ann"));
+        form.advance(&mut progress, Input::Text("12"));
+        assert_eq!(progress.step, 1);
+        assert_eq!(progress.error.as_deref(), Some("adults only"));
+        assert!(form.render(&progress).0.ends_with("⚠️ adults only"));
+
+        form.advance(&mut progress, Input::Skip);
+        form.advance(&mut progress, Input::Skip);
+        assert_eq!(progress.step, 2);
+
+        form.advance(&mut progress, Input::Text("31.12.1990"));
+        form.advance(&mut progress, Input::Back);
+        form.advance(&mut progress, Input::Text("1.1.1991"));
+        assert_eq!(
+            progress.answers["born"].as_date(),
+            NaiveDate::from_ymd_opt(1991, 1, 1)
+        );
+
+        form.advance(&mut progress, Input::Choice(1));
+        let (text, _) = form.render(&progress);
+        assert!(text.contains("born: 01.01.1991\nplan: Pro"));
+        assert!(text.contains("age: —"));
+
+        let FormOutcome::Submitted(answers) = form.advance(&mut progress, Input::Confirm) else {
+            panic!("should submit");
+        };
+        assert_eq!(answers["plan"], FieldValue::Choice("pro".to_owned()));
+        assert!(!answers.contains_key("age"));
+    }
+
+    #[test]
+    fn rejects_non_finite_numbers() -> anyhow::Result<()> {
+        let form = form();
+        let mut progress = FormProgress {
+            step: 1,
+            ..Default::default()
+        };
+
+        for text in ["nan", "inf", "-inf", "1e999"] {
+            form.advance(&mut progress, Input::Text(text));
+            assert_eq!(progress.step, 1);
+            assert_eq!(progress.error.as_deref(), Some("please send a number"));
+        }
+
+        form.advance(&mut progress, Input::Text("18,5"));
+        assert_eq!(progress.answers["age"], FieldValue::Number(18.5));
+
+        let saved: FormProgress = serde_json::from_str(&serde_json::to_string(&progress)?)?;
+        assert_eq!(saved.answers, progress.answers);
+
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_bad_forms() {
+        let name = || Field::text("name", "Your name?");
+
+        assert!(Form::new("x".repeat(MAX_ID_LEN + 1), "Too long", [name()]).is_err());
+        assert!(Form::new("empty", "Empty", []).is_err());
+        assert!(Form::new("twice", "Twice", [name(), name()]).is_err());
+        assert!(Form::new("x".repeat(MAX_ID_LEN), "Fine", [name()]).is_ok());
+    }
+}
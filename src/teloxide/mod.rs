@@ -1,5 +1,16 @@
+pub mod dialogue_machine;
+pub mod dialogue_migrations;
 pub mod dialogue_state;
+pub mod edit_scheduler;
+#[cfg(test)]
+mod fake_api;
+pub mod form;
+#[cfg(feature = "process")]
+#[doc(cfg(process))]
+pub mod process_admin;
 pub mod progress_message;
+pub mod rich_text;
+pub mod split;
 pub mod text_match;
 pub mod typer;
 pub mod updateable_message;
@@ -0,0 +1,458 @@
+use std::{
+    collections::HashSet,
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::Arc,
+    time::Duration,
+};
+
+use teloxide::{
+    dispatching::{UpdateFilterExt, UpdateHandler},
+    dptree,
+    payloads::{AnswerCallbackQuerySetters, SendMessageSetters},
+    requests::Requester,
+    types::{CallbackQuery, ChatId, InlineKeyboardButton, InlineKeyboardMarkup, Message, Update},
+    Bot,
+};
+use tokio::{sync::broadcast::error::RecvError, time::Instant};
+
+use crate::process::{LogStream, ProcessRunner, Supervisor};
+
+use super::updateable_message::UpdateableMessage;
+
+const CALLBACK_PREFIX: &str = "procadm";
+const MAX_TEXT: usize = 3500;
+// telegram's limit, in bytes
+const MAX_CALLBACK_DATA: usize = 64;
+
+pub struct ProcessAdmin {
+    pub supervisor: Arc<Supervisor>,
+    pub allowed_chats: HashSet<ChatId>,
+    pub tail_lines: usize,
+    pub follow_for: Duration,
+}
+
+#[derive(Debug, PartialEq)]
+enum Action {
+    Ps,
+    Stop(String),
+    Restart(String),
+    Logs(String),
+}
+
+impl Action {
+    fn parse(text: &str) -> Option<Self> {
+        let mut words = text.split_whitespace();
+
+        // `/logs@my_bot name` works too
+        let command = words.next()?.split('@').next()?;
+        let name = words.next().map(ToOwned::to_owned);
+
+        Some(match (command, name) {
+            ("/ps", _) => Action::Ps,
+            ("/stop", Some(name)) => Action::Stop(name),
+            ("/restart", Some(name)) => Action::Restart(name),
+            ("/logs", Some(name)) => Action::Logs(name),
+            _ => return None,
+        })
+    }
+
+    fn parse_callback(data: &str) -> Option<Self> {
+        let mut parts = data.splitn(3, ':');
+
+        if parts.next()? != CALLBACK_PREFIX {
+            return None;
+        }
+
+        match Self::parse(&format!("/{} {}", parts.next()?, parts.next()?))? {
+            Action::Ps => None,
+            action => Some(action),
+        }
+    }
+}
+
+fn callback_data(action: &str, name: &str) -> String {
+    let data = format!("{}:{}:{}", CALLBACK_PREFIX, action, name);
+
+    if data.len() <= MAX_CALLBACK_DATA {
+        return data;
+    }
+
+    // doesn't fit, so it's looked up by hash when pressed, see `ProcessAdmin::resolve`
+    format!("{}:{}:#{:016x}", CALLBACK_PREFIX, action, name_hash(name))
+}
+
+fn name_hash(name: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    name.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn buttons(name: &str) -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new([[
+        InlineKeyboardButton::callback("🔄 Logs", callback_data("logs", name)),
+        InlineKeyboardButton::callback("♻️ Restart", callback_data("restart", name)),
+        InlineKeyboardButton::callback("⏹ Stop", callback_data("stop", name)),
+    ]])
+}
+
+impl ProcessAdmin {
+    pub fn new(
+        supervisor: Arc<Supervisor>,
+        allowed_chats: impl IntoIterator<Item = ChatId>,
+    ) -> Self {
+        Self {
+            supervisor,
+            allowed_chats: allowed_chats.into_iter().collect(),
+            tail_lines: 30,
+            follow_for: Duration::from_secs(120),
+        }
+    }
+
+    // a runner that isn't under a supervisor yet
+    pub async fn for_runner(
+        runner: ProcessRunner,
+        allowed_chats: impl IntoIterator<Item = ChatId>,
+    ) -> anyhow::Result<Self> {
+        let supervisor = Supervisor::new(runner.worker_id.clone(), runner.state_dir.clone());
+        supervisor.adopt(runner).await?;
+
+        Ok(Self::new(Arc::new(supervisor), allowed_chats))
+    }
+
+    pub fn handler(self: Arc<Self>) -> UpdateHandler<anyhow::Error> {
+        let messages = {
+            let filter_admin = self.clone();
+            let admin = self.clone();
+
+            Update::filter_message()
+                .filter(move |msg: Message| {
+                    filter_admin.allowed_chats.contains(&msg.chat.id)
+                        && msg.text().and_then(Action::parse).is_some()
+                })
+                .endpoint(move |bot: Bot, msg: Message| {
+                    let admin = admin.clone();
+                    async move { admin.handle_message(&bot, &msg).await.map(|_| ()) }
+                })
+        };
+
+        let callbacks = {
+            let filter_admin = self.clone();
+            let admin = self;
+
+            Update::filter_callback_query()
+                .filter(move |q: CallbackQuery| {
+                    q.message
+                        .as_ref()
+                        .is_some_and(|msg| filter_admin.allowed_chats.contains(&msg.chat.id))
+                        && q.data.as_deref().and_then(Action::parse_callback).is_some()
+                })
+                .endpoint(move |bot: Bot, q: CallbackQuery| {
+                    let admin = admin.clone();
+                    async move { admin.handle_callback(&bot, &q).await.map(|_| ()) }
+                })
+        };
+
+        dptree::entry().branch(messages).branch(callbacks)
+    }
+
+    // for bots that do their own routing; returns whether the message was an admin command
+    pub async fn handle_message(&self, bot: &Bot, msg: &Message) -> anyhow::Result<bool> {
+        if !self.allowed_chats.contains(&msg.chat.id) {
+            return Ok(false);
+        }
+
+        let Some(action) = msg.text().and_then(Action::parse) else {
+            return Ok(false);
+        };
+
+        let chat_id = msg.chat.id;
+
+        let reply = match action {
+            Action::Ps => self.ps().await?,
+            Action::Logs(name) => {
+                self.send_logs(bot, chat_id, name).await?;
+                return Ok(true);
+            }
+            action => self.run(action).await,
+        };
+
+        bot.send_message(chat_id, reply).await?;
+
+        Ok(true)
+    }
+
+    pub async fn handle_callback(&self, bot: &Bot, q: &CallbackQuery) -> anyhow::Result<bool> {
+        let Some(msg) = &q.message else {
+            return Ok(false);
+        };
+
+        if !self.allowed_chats.contains(&msg.chat.id) {
+            return Ok(false);
+        }
+
+        let Some(action) = q.data.as_deref().and_then(Action::parse_callback) else {
+            return Ok(false);
+        };
+
+        let action = match action {
+            Action::Stop(name) => Action::Stop(self.resolve(name).await),
+            Action::Restart(name) => Action::Restart(self.resolve(name).await),
+            Action::Logs(name) => Action::Logs(self.resolve(name).await),
+            Action::Ps => Action::Ps,
+        };
+
+        let Action::Logs(name) = action else {
+            let reply = self.run(action).await;
+            bot.answer_callback_query(&q.id).text(reply).await?;
+            return Ok(true);
+        };
+
+        bot.answer_callback_query(&q.id).await?;
+
+        let mut message = UpdateableMessage {
+            chat_id: msg.chat.id,
+            message_id: msg.id,
+            text: msg.text().unwrap_or_default().to_owned(),
+            reply_markup: Some(buttons(&name)),
+            continuations: vec![],
+            parse_mode: None,
+            scheduler: None,
+        };
+
+        self.render_logs(bot, &mut message, &name).await?;
+
+        Ok(true)
+    }
+
+    // names too long for callback data come back hashed
+    async fn resolve(&self, name: String) -> String {
+        let names = self.supervisor.names().await;
+
+        if names.contains(&name) {
+            return name;
+        }
+
+        let Some(hash) = name
+            .strip_prefix('#')
+            .and_then(|hash| u64::from_str_radix(hash, 16).ok())
+        else {
+            return name;
+        };
+
+        names
+            .into_iter()
+            .find(|n| name_hash(n) == hash)
+            .unwrap_or(name)
+    }
+
+    async fn ps(&self) -> anyhow::Result<String> {
+        let mut lines = vec![];
+
+        for runner in self.supervisor.runners().await {
+            let status = runner.get_status().await?;
+
+            lines.push(format!(
+                "{} {}{} health={}",
+                if status["running"].as_bool().unwrap_or(false) {
+                    "🟢"
+                } else {
+                    "🔴"
+                },
+                runner.name,
+                status["pid"]
+                    .as_u64()
+                    .map(|pid| format!(" pid={}", pid))
+                    .unwrap_or_default(),
+                status["health"]["status"].as_str().unwrap_or("unknown"),
+            ));
+        }
+
+        if lines.is_empty() {
+            return Ok("no processes".to_owned());
+        }
+
+        Ok(lines.join("\n"))
+    }
+
+    async fn run(&self, action: Action) -> String {
+        let (verb, result, name) = match action {
+            Action::Stop(name) => ("stopped", self.supervisor.stop(&name).await, name),
+            Action::Restart(name) => ("restarted", self.supervisor.restart(&name).await, name),
+            Action::Ps | Action::Logs(_) => return format!("can't run {:?}", action),
+        };
+
+        match result {
+            Ok(()) => format!("{} {}", verb, name),
+            Err(e) => format!("{}: {}", name, e),
+        }
+    }
+
+    async fn render_logs(
+        &self,
+        bot: &Bot,
+        message: &mut UpdateableMessage,
+        name: &str,
+    ) -> anyhow::Result<()> {
+        let Some(runner) = self.supervisor.runner(name).await else {
+            return message
+                .update_text(bot, &format!("no such process: {}", name))
+                .await;
+        };
+
+        message
+            .update_text(bot, &logs_text(&runner, self.tail_lines).await)
+            .await
+    }
+
+    async fn send_logs(&self, bot: &Bot, chat_id: ChatId, name: String) -> anyhow::Result<()> {
+        let Some(runner) = self.supervisor.runner(&name).await else {
+            bot.send_message(chat_id, format!("no such process: {}", name))
+                .await?;
+            return Ok(());
+        };
+
+        let mut message = UpdateableMessage::new(
+            bot.send_message(chat_id, logs_text(&runner, self.tail_lines).await)
+                .reply_markup(buttons(&name)),
+        )
+        .await?;
+
+        let bot = bot.clone();
+        let tail_lines = self.tail_lines;
+        let until = Instant::now() + self.follow_for;
+
+        // keep the tail live for a while
+        tokio::spawn(async move {
+            let mut lines = runner.subscribe_logs();
+
+            loop {
+                match tokio::time::timeout_at(until, lines.recv()).await {
+                    Err(_) | Ok(Err(RecvError::Closed)) => break,
+                    Ok(_) => {}
+                }
+
+                // let a burst of lines accumulate into one edit
+                tokio::time::sleep(Duration::from_secs(2)).await;
+                lines = lines.resubscribe();
+
+                let text = logs_text(&runner, tail_lines).await;
+                if let Err(e) = message.update_text(&bot, &text).await {
+                    println!("error following logs of {}: {}", runner.name, e);
+                    break;
+                }
+            }
+        });
+
+        Ok(())
+    }
+}
+
+async fn logs_text(runner: &ProcessRunner, tail_lines: usize) -> String {
+    let mut text = format!("📜 {}\n", runner.name);
+
+    for stream in [LogStream::Stdout, LogStream::Stderr] {
+        let tail = runner.tail(stream, tail_lines).await;
+
+        if !tail.is_empty() {
+            text.push_str(&format!("\n{:?}:\n{}\n", stream, tail.join("\n")));
+        }
+    }
+
+    if text.len() > MAX_TEXT {
+        let mut cut = text.len() - MAX_TEXT;
+        while !text.is_char_boundary(cut) {
+            cut += 1;
+        }
+        text = format!("…{}", &text[cut..]);
+    }
+
+    text
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{process::SupervisorConfig, teloxide::fake_api};
+
+    #[test]
+    fn parses_commands_and_callbacks() {
+        assert_eq!(Action::parse("/ps"), Some(Action::Ps));
+        assert_eq!(
+            Action::parse("/logs@my_bot web extra"),
+            Some(Action::Logs("web".into()))
+        );
+        assert_eq!(Action::parse("/stop"), None);
+        // deep links are the bot's own business
+        assert_eq!(Action::parse("/start web"), None);
+        assert_eq!(Action::parse("ps"), None);
+
+        assert_eq!(
+            Action::parse_callback(&callback_data("restart", "web")),
+            Some(Action::Restart("web".into()))
+        );
+        assert_eq!(
+            Action::parse_callback(&callback_data("stop", "a:b")),
+            Some(Action::Stop("a:b".into()))
+        );
+        assert_eq!(Action::parse_callback("other:stop:web"), None);
+        assert_eq!(Action::parse_callback(CALLBACK_PREFIX), None);
+        assert_eq!(Action::parse_callback("procadm:ps:web"), None);
+    }
+
+    #[tokio::test]
+    async fn hashes_long_names() -> anyhow::Result<()> {
+        let name = "a-very-long-process-name-that-would-not-fit-in-callback-data";
+
+        let state_dir = std::env::temp_dir().join(format!("admin-hash-{}", std::process::id()));
+        let config = SupervisorConfig::from_toml(&format!(
+            r#"
+            worker_id = "test"
+            state_dir = "{}"
+
+            [[process]]
+            name = "{}"
+            command = "true"
+            "#,
+            state_dir.display(),
+            name
+        ))?;
+        let admin = ProcessAdmin::new(Arc::new(Supervisor::from_config(config)?), []);
+
+        let data = callback_data("restart", name);
+        assert!(data.len() <= MAX_CALLBACK_DATA);
+
+        let Some(Action::Restart(hashed)) = Action::parse_callback(&data) else {
+            panic!("{} didn't parse", data);
+        };
+        assert_eq!(admin.resolve(hashed).await, name);
+        assert_eq!(admin.resolve("#0".into()).await, "#0");
+
+        let _ = std::fs::remove_dir_all(&state_dir);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn answers_only_allowed_chats() -> anyhow::Result<()> {
+        let (bot, requests) =
+            fake_api::serve(|_, payload| fake_api::message(2, payload["text"].as_str().unwrap()))
+                .await?;
+        let msg: Message = serde_json::from_value(fake_api::message(1, "/ps")["result"].take())?;
+
+        let state_dir = std::env::temp_dir().join(format!("admin-test-{}", std::process::id()));
+        let supervisor = Arc::new(Supervisor::new("test".into(), &state_dir));
+
+        let admin = ProcessAdmin::new(supervisor.clone(), [ChatId(2)]);
+        assert!(!admin.handle_message(&bot, &msg).await?);
+        assert!(requests.lock().is_empty());
+
+        let admin = ProcessAdmin::new(supervisor, [ChatId(1)]);
+        assert!(admin.handle_message(&bot, &msg).await?);
+        assert_eq!(requests.lock().len(), 1);
+        assert_eq!(requests.lock()[0].1["text"], "no processes");
+
+        let _ = std::fs::remove_dir_all(&state_dir);
+
+        Ok(())
+    }
+}
@@ -1,49 +1,314 @@
-use std::{sync::Arc, time::Duration};
+use std::{future::Future, sync::Arc, time::Duration};
 
 use teloxide::{
-    requests::{JsonRequest, Request},
-    Bot,
+    payloads::{EditMessageText, SendMessage},
+    requests::{JsonRequest, Request, Requester},
+    types::{MessageEntity, MessageId, ParseMode, Recipient},
+    ApiError, Bot, RequestError,
 };
 use tokio::{sync::Mutex, time::Instant};
 
-const THROTTLE: Duration = Duration::from_millis(200);
+use super::{
+    edit_scheduler::EditScheduler,
+    rich_text::{escape_markdown, RichText},
+    split::{split_message, MAX_MESSAGE_LEN},
+};
+
+const MAX_ATTEMPTS: u32 = 5;
+const RETRY_DELAY: Duration = Duration::from_secs(1);
 
 pub struct ProgressMessage {
     pub bot: Bot,
-    pub edit_payload: teloxide::payloads::EditMessageText,
+    pub edit_payload: EditMessageText,
     pub schedule: Arc<Mutex<ScheduleStatus>>,
+    delivery: Arc<parking_lot::Mutex<Delivery>>,
+    scheduler: Arc<EditScheduler>,
 }
 
 pub enum ScheduleStatus {
     LastUpdate(Instant),
-    NeedsUpdate(Bot, teloxide::payloads::EditMessageText),
+    NeedsUpdate(Bot, EditMessageText),
+    // an edit is in flight and nothing newer is waiting
+    Sending,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeliveryStatus {
+    // text past the length limit spills into continuation messages; a part that was deleted
+    // is sent again, together with the ones after it
+    pub message_ids: Vec<MessageId>,
+    // updates given up on since the last one that got through
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+}
+
+impl DeliveryStatus {
+    pub fn is_failing(&self) -> bool {
+        self.consecutive_failures > 0
+    }
+}
+
+struct Delivery {
+    status: DeliveryStatus,
+    // what each of `status.message_ids` shows
+    delivered: Vec<Part>,
+}
+
+type Part = (String, Option<Vec<MessageEntity>>);
+
+impl Delivery {
+    fn push(&mut self, message_id: MessageId, part: Part) {
+        self.status.message_ids.push(message_id);
+        self.delivered.push(part);
+    }
+
+    fn truncate(&mut self, len: usize) -> Vec<MessageId> {
+        self.delivered.truncate(len);
+        self.status
+            .message_ids
+            .split_off(len.min(self.status.message_ids.len()))
+    }
+}
+
+pub(super) fn is_gone(e: &RequestError) -> bool {
+    matches!(
+        e,
+        RequestError::Api(
+            ApiError::MessageToEditNotFound
+                | ApiError::MessageIdInvalid
+                | ApiError::MessageCantBeEdited
+        )
+    )
+}
+
+// worth another try; anything else, like a 400 for bad markup, would fail the same way again
+fn is_transient(e: &RequestError) -> bool {
+    matches!(
+        e,
+        RequestError::RetryAfter(_) | RequestError::Network(_) | RequestError::Io(_)
+    )
+}
+
+pub(super) async fn with_retries<T, F>(
+    scheduler: &EditScheduler,
+    chat_id: &Recipient,
+    mut request: impl FnMut() -> F,
+) -> Result<T, RequestError>
+where
+    F: Future<Output = Result<T, RequestError>>,
+{
+    let mut attempt = 1;
+
+    loop {
+        scheduler.acquire(chat_id).await;
+
+        let error = match request().await {
+            Ok(result) => return Ok(result),
+            Err(e) => e,
+        };
+
+        if !is_transient(&error) || attempt >= MAX_ATTEMPTS {
+            return Err(error);
+        }
+
+        // pausing the whole chat, so other messages in it hold off too
+        match &error {
+            RequestError::RetryAfter(after) => scheduler.pause(chat_id, *after),
+            _ => tokio::time::sleep(RETRY_DELAY * attempt).await,
+        }
+
+        attempt += 1;
+    }
+}
+
+fn as_new_message(edit: &EditMessageText) -> SendMessage {
+    let mut msg = SendMessage::new(edit.chat_id.clone(), edit.text.clone());
+    msg.parse_mode = edit.parse_mode;
+    msg.entities = edit.entities.clone();
+    msg.disable_web_page_preview = edit.disable_web_page_preview;
+    msg
+}
+
+async fn delete_all(
+    bot: &Bot,
+    scheduler: &EditScheduler,
+    chat_id: &Recipient,
+    message_ids: Vec<MessageId>,
+) {
+    for message_id in message_ids {
+        scheduler.acquire(chat_id).await;
+        let _ = bot.delete_message(chat_id.clone(), message_id).await;
+    }
 }
 
-async fn updater_future(wait_for: Instant, schedule: Arc<Mutex<ScheduleStatus>>) {
-    if wait_for > Instant::now() + Duration::from_millis(50) {
-        tokio::time::sleep_until(wait_for).await;
+async fn deliver(
+    bot: &Bot,
+    edit: EditMessageText,
+    delivery: &parking_lot::Mutex<Delivery>,
+    scheduler: &EditScheduler,
+) -> Result<(), RequestError> {
+    let parts = split_message(
+        &edit.text,
+        edit.parse_mode,
+        edit.entities.as_deref(),
+        MAX_MESSAGE_LEN,
+    );
+
+    for (i, split) in parts.iter().enumerate() {
+        let (message_id, delivered) = {
+            let delivery = delivery.lock();
+            (
+                delivery.status.message_ids.get(i).copied(),
+                delivery.delivered.get(i) == Some(split),
+            )
+        };
+
+        // a streamed answer only ever changes the last part or two
+        if delivered {
+            continue;
+        }
+
+        let mut part = edit.clone();
+        (part.text, part.entities) = split.clone();
+
+        if let Some(message_id) = message_id {
+            part.message_id = message_id;
+
+            let edited = with_retries(scheduler, &edit.chat_id, || {
+                let req = JsonRequest::new(bot.clone(), part.clone());
+
+                async move {
+                    match req.send().await {
+                        Ok(_) | Err(RequestError::Api(ApiError::MessageNotModified)) => Ok(()),
+                        Err(e) => Err(e),
+                    }
+                }
+            })
+            .await;
+
+            match edited {
+                Ok(()) => {
+                    delivery.lock().delivered[i] = split.clone();
+                    continue;
+                }
+                Err(e) if is_gone(&e) => {
+                    let stale = delivery.lock().truncate(i);
+                    delete_all(bot, scheduler, &edit.chat_id, stale).await;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        let msg = as_new_message(&part);
+        let message = with_retries(scheduler, &edit.chat_id, || {
+            JsonRequest::new(bot.clone(), msg.clone()).send()
+        })
+        .await?;
+        delivery.lock().push(message.id, split.clone());
     }
 
-    let mut schedule = schedule.lock().await;
+    let stale = delivery.lock().truncate(parts.len());
+    delete_all(bot, scheduler, &edit.chat_id, stale).await;
+
+    Ok(())
+}
 
-    match &mut *schedule {
-        ScheduleStatus::LastUpdate(_) => {}
-        ScheduleStatus::NeedsUpdate(bot, update) => {
-            JsonRequest::new(bot.clone(), update.clone())
-                .send()
-                .await
-                .unwrap();
-            *schedule = ScheduleStatus::LastUpdate(Instant::now());
+async fn updater_future(
+    mut wait_for: Instant,
+    chat_id: Recipient,
+    schedule: Arc<Mutex<ScheduleStatus>>,
+    delivery: Arc<parking_lot::Mutex<Delivery>>,
+    scheduler: Arc<EditScheduler>,
+) {
+    loop {
+        if wait_for > Instant::now() + Duration::from_millis(50) {
+            tokio::time::sleep_until(wait_for).await;
+        }
+
+        // updates keep coming in while the chat is out of budget; only the last one gets sent
+        scheduler.ready(&chat_id).await;
+
+        // not holding the lock while sending, so `update` doesn't wait out a RetryAfter
+        let (bot, edit) = {
+            let mut schedule = schedule.lock().await;
+
+            match std::mem::replace(&mut *schedule, ScheduleStatus::Sending) {
+                ScheduleStatus::NeedsUpdate(bot, edit) => (bot, edit),
+                other => {
+                    *schedule = other;
+                    return;
+                }
+            }
+        };
+
+        let result = deliver(&bot, edit, &delivery, &scheduler).await;
+
+        {
+            let status = &mut delivery.lock().status;
+
+            match result {
+                Ok(()) => {
+                    status.consecutive_failures = 0;
+                    status.last_error = None;
+                }
+                Err(e) => {
+                    eprintln!("Error updating progress message: {}", e);
+                    status.consecutive_failures += 1;
+                    status.last_error = Some(e.to_string());
+                }
+            }
+        }
+
+        let mut schedule = schedule.lock().await;
+
+        match &*schedule {
+            ScheduleStatus::NeedsUpdate(..) => {
+                wait_for = Instant::now() + scheduler.limits().throttle
+            }
+            _ => {
+                *schedule = ScheduleStatus::LastUpdate(Instant::now());
+                return;
+            }
         }
     }
 }
 
 impl ProgressMessage {
-    pub async fn new(bot: Bot, msg: teloxide::payloads::SendMessage) -> anyhow::Result<Self> {
-        let result = JsonRequest::new(bot.clone(), msg.clone()).send().await?;
+    pub async fn new(bot: Bot, msg: SendMessage) -> anyhow::Result<Self> {
+        let scheduler = EditScheduler::global();
+        let parts = split_message(
+            &msg.text,
+            msg.parse_mode,
+            msg.entities.as_deref(),
+            MAX_MESSAGE_LEN,
+        );
+        let mut delivery = Delivery {
+            status: DeliveryStatus {
+                message_ids: vec![],
+                consecutive_failures: 0,
+                last_error: None,
+            },
+            delivered: vec![],
+        };
 
-        let message_id = result.id;
-        let edit_payload = teloxide::payloads::EditMessageText {
+        for (i, split) in parts.iter().enumerate() {
+            let mut part = msg.clone();
+            (part.text, part.entities) = split.clone();
+
+            if i + 1 < parts.len() {
+                part.reply_markup = None;
+            }
+
+            scheduler.acquire(&msg.chat_id).await;
+            let result = JsonRequest::new(bot.clone(), part).send().await?;
+            delivery.push(result.id, split.clone());
+        }
+
+        let Some(&message_id) = delivery.status.message_ids.first() else {
+            return Err(anyhow::anyhow!("message has no text to send"));
+        };
+
+        let edit_payload = EditMessageText {
             chat_id: msg.chat_id,
             message_id,
             text: msg.text,
@@ -57,24 +322,63 @@ impl ProgressMessage {
             bot,
             edit_payload,
             schedule: Arc::new(Mutex::new(ScheduleStatus::LastUpdate(Instant::now()))),
+            delivery: Arc::new(parking_lot::Mutex::new(delivery)),
+            scheduler,
             // needs_update: false,
             // update_scheduled: false,
         })
     }
 
+    // instead of the global one, e.g. for a second bot
+    pub fn with_scheduler(mut self, scheduler: Arc<EditScheduler>) -> Self {
+        self.scheduler = scheduler;
+        self
+    }
+
+    pub fn status(&self) -> DeliveryStatus {
+        self.delivery.lock().status.clone()
+    }
+
+    async fn schedule(&mut self) {
+        if let Some(&message_id) = self.delivery.lock().status.message_ids.first() {
+            self.edit_payload.message_id = message_id;
+        }
+
+        let mut schedule = self.schedule.lock().await;
+
+        if let ScheduleStatus::LastUpdate(last) = &*schedule {
+            let wait_for = *last + self.scheduler.limits().throttle;
+            let chat_id = self.edit_payload.chat_id.clone();
+            let sched = self.schedule.clone();
+            let delivery = self.delivery.clone();
+            let scheduler = self.scheduler.clone();
+            tokio::spawn(async move {
+                updater_future(wait_for, chat_id, sched, delivery, scheduler).await
+            });
+        }
+
+        *schedule = ScheduleStatus::NeedsUpdate(self.bot.clone(), self.edit_payload.clone());
+    }
+
     pub async fn update(&mut self, new_text: &str) {
         if self.edit_payload.text != new_text {
             self.edit_payload.text = new_text.to_owned();
+            self.schedule().await;
+        }
+    }
 
-            let mut schedule = self.schedule.lock().await;
+    // rendered for the message's parse mode; without one it's sent as entities
+    pub async fn update_rich(&mut self, text: &RichText) {
+        let (text, entities) = text.render(self.edit_payload.parse_mode);
 
-            if let ScheduleStatus::LastUpdate(last) = &*schedule {
-                let wait_for = *last + THROTTLE;
-                let sched = self.schedule.clone();
-                tokio::spawn(async move { updater_future(wait_for, sched).await });
-            }
+        if entities.is_some() {
+            self.edit_payload.parse_mode = None;
+        }
 
-            *schedule = ScheduleStatus::NeedsUpdate(self.bot.clone(), self.edit_payload.clone());
+        if self.edit_payload.text != text || self.edit_payload.entities != entities {
+            self.edit_payload.text = text;
+            self.edit_payload.entities = entities;
+            self.schedule().await;
         }
     }
 
@@ -86,7 +390,185 @@ impl ProgressMessage {
 
     pub async fn append_md(&mut self, new_text: &str) {
         let mut text = self.edit_payload.text.clone();
-        text.push_str(&teloxide::utils::markdown::escape(new_text));
+        text.push_str(&escape_markdown(new_text));
+        self.update(&text).await;
+    }
+
+    pub async fn append_rich(&mut self, new_text: &RichText) {
+        let mut text = self.edit_payload.text.clone();
+
+        #[allow(deprecated)]
+        match self.edit_payload.parse_mode {
+            Some(ParseMode::MarkdownV2) => text.push_str(&new_text.to_markdown_v2()),
+            Some(ParseMode::Html) => text.push_str(&new_text.to_html()),
+            Some(ParseMode::Markdown) => text.push_str(&new_text.plain_text()),
+            None => {
+                let mut entities = self.edit_payload.entities.clone().unwrap_or_default();
+                new_text.append_to(&mut text, &mut entities);
+
+                if self.edit_payload.text != text {
+                    self.edit_payload.text = text;
+                    self.edit_payload.entities = Some(entities);
+                    self.schedule().await;
+                }
+                return;
+            }
+        }
+
         self.update(&text).await;
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+
+    use teloxide::types::ChatId;
+
+    use super::*;
+    use crate::teloxide::{
+        edit_scheduler::{EditLimits, Rate},
+        fake_api,
+    };
+
+    fn unlimited() -> Arc<EditScheduler> {
+        Arc::new(EditScheduler::new(EditLimits {
+            per_chat: Rate::new(100, Duration::from_secs(1)),
+            ..Default::default()
+        }))
+    }
+
+    async fn settle(progress: &ProgressMessage) {
+        loop {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+
+            if let ScheduleStatus::LastUpdate(_) = &*progress.schedule.lock().await {
+                return;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn recovers_from_api_errors() -> anyhow::Result<()> {
+        let throttled = AtomicBool::new(false);
+
+        let (bot, requests) = fake_api::serve(move |method, payload| {
+            match (method, payload["text"].as_str().unwrap_or_default()) {
+                ("SendMessage", text) if text.starts_with("second") => fake_api::message(2, text),
+                ("SendMessage", text) => fake_api::message(1, text),
+                ("EditMessageText", "first, throttled")
+                    if !throttled.swap(true, Ordering::SeqCst) =>
+                {
+                    fake_api::retry_after(1)
+                }
+                ("EditMessageText", "second") => {
+                    fake_api::error(400, "Bad Request: message to edit not found")
+                }
+                ("EditMessageText", "bad") => {
+                    fake_api::error(400, "Bad Request: can't parse entities: unclosed tag")
+                }
+                (_, "same") => fake_api::error(
+                    400,
+                    "Bad Request: message is not modified: specified new message content and \
+                     reply markup are exactly the same as a current content and reply markup \
+                     of the message",
+                ),
+                (_, text) => fake_api::message(1, text),
+            }
+        })
+        .await?;
+
+        let mut progress = ProgressMessage::new(bot, SendMessage::new(ChatId(1), "first"))
+            .await?
+            .with_scheduler(unlimited());
+
+        progress.update("first, throttled").await;
+        settle(&progress).await;
+        assert!(!progress.status().is_failing());
+
+        progress.update("same").await;
+        settle(&progress).await;
+        assert!(!progress.status().is_failing());
+
+        progress.update("second").await;
+        settle(&progress).await;
+        assert_eq!(progress.status().message_ids, vec![MessageId(2)]);
+
+        let edits = requests
+            .lock()
+            .iter()
+            .filter(|(method, _)| method == "EditMessageText")
+            .count();
+        assert_eq!(edits, 4);
+
+        // reported right away, not retried
+        let started = Instant::now();
+        progress.update("bad").await;
+        settle(&progress).await;
+        assert!(progress.status().is_failing());
+        assert!(started.elapsed() < RETRY_DELAY);
+
+        let edits = requests
+            .lock()
+            .iter()
+            .filter(|(_, payload)| payload["text"] == "bad")
+            .count();
+        assert_eq!(edits, 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn spills_into_continuations() -> anyhow::Result<()> {
+        let next_id = AtomicI32::new(1);
+
+        let (bot, requests) = fake_api::serve(move |method, payload| match method {
+            "SendMessage" => fake_api::message(
+                next_id.fetch_add(1, Ordering::SeqCst),
+                payload["text"].as_str().unwrap_or_default(),
+            ),
+            "DeleteMessage" => fake_api::ok(),
+            _ => fake_api::message(1, payload["text"].as_str().unwrap_or_default()),
+        })
+        .await?;
+
+        let mut progress = ProgressMessage::new(bot, SendMessage::new(ChatId(1), ""))
+            .await?
+            .with_scheduler(unlimited());
+
+        let paragraph = format!("{}\n\n", "word ".repeat(300));
+        progress.update(&paragraph.repeat(3)).await;
+        settle(&progress).await;
+        assert_eq!(
+            progress.status().message_ids,
+            vec![MessageId(1), MessageId(2)]
+        );
+
+        // only the last part changes
+        progress.append("more").await;
+        settle(&progress).await;
+
+        progress.update("short").await;
+        settle(&progress).await;
+        assert_eq!(progress.status().message_ids, vec![MessageId(1)]);
+
+        let methods: Vec<_> = requests
+            .lock()
+            .iter()
+            .map(|(method, _)| method.clone())
+            .collect();
+        assert_eq!(
+            methods,
+            [
+                "SendMessage",
+                "EditMessageText",
+                "SendMessage",
+                "EditMessageText",
+                "EditMessageText",
+                "DeleteMessage"
+            ]
+        );
+
+        Ok(())
+    }
+}
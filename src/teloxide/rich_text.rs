@@ -0,0 +1,414 @@
+use teloxide::types::{MessageEntity, MessageEntityKind, ParseMode, UserId};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Style {
+    Bold,
+    Italic,
+    Underline,
+    Strikethrough,
+    Spoiler,
+    Quote,
+    Link(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Node {
+    Text(String),
+    Code(String),
+    Pre {
+        code: String,
+        language: Option<String>,
+    },
+    Styled(Style, RichText),
+}
+
+// formatted text that's escaped for whichever way it's sent
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RichText {
+    nodes: Vec<Node>,
+}
+
+impl From<&str> for RichText {
+    fn from(text: &str) -> Self {
+        Self::new().text(text)
+    }
+}
+
+impl From<String> for RichText {
+    fn from(text: String) -> Self {
+        Self::new().text(text)
+    }
+}
+
+pub fn escape_markdown(text: &str) -> String {
+    escape_with(text, "\\_*[]()~`>#+-=|{}.!")
+}
+
+fn escape_with(text: &str, special: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        if special.contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn push_text(text: &mut String, units: &mut usize, s: &str) {
+    text.push_str(s);
+    *units += s.encode_utf16().count();
+}
+
+// `_` and `__` next to each other are ambiguous; an empty "\r" in between keeps them apart
+fn push_underscores(out: &mut String, marker: &str) {
+    if out.ends_with('_') {
+        out.push('\r');
+    }
+
+    out.push_str(marker);
+}
+
+impl RichText {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn text(mut self, text: impl Into<String>) -> Self {
+        self.nodes.push(Node::Text(text.into()));
+        self
+    }
+
+    pub fn styled(mut self, style: Style, inner: impl Into<RichText>) -> Self {
+        self.nodes.push(Node::Styled(style, inner.into()));
+        self
+    }
+
+    pub fn bold(self, inner: impl Into<RichText>) -> Self {
+        self.styled(Style::Bold, inner)
+    }
+
+    pub fn italic(self, inner: impl Into<RichText>) -> Self {
+        self.styled(Style::Italic, inner)
+    }
+
+    pub fn underline(self, inner: impl Into<RichText>) -> Self {
+        self.styled(Style::Underline, inner)
+    }
+
+    pub fn strikethrough(self, inner: impl Into<RichText>) -> Self {
+        self.styled(Style::Strikethrough, inner)
+    }
+
+    pub fn spoiler(self, inner: impl Into<RichText>) -> Self {
+        self.styled(Style::Spoiler, inner)
+    }
+
+    pub fn quote(self, inner: impl Into<RichText>) -> Self {
+        self.styled(Style::Quote, inner)
+    }
+
+    pub fn link(self, inner: impl Into<RichText>, url: impl Into<String>) -> Self {
+        self.styled(Style::Link(url.into()), inner)
+    }
+
+    pub fn mention(self, inner: impl Into<RichText>, user_id: UserId) -> Self {
+        self.link(inner, format!("tg://user?id={}", user_id))
+    }
+
+    pub fn code(mut self, code: impl Into<String>) -> Self {
+        self.nodes.push(Node::Code(code.into()));
+        self
+    }
+
+    pub fn pre(mut self, code: impl Into<String>, language: Option<&str>) -> Self {
+        self.nodes.push(Node::Pre {
+            code: code.into(),
+            language: language.map(ToOwned::to_owned),
+        });
+        self
+    }
+
+    pub fn append(mut self, other: RichText) -> Self {
+        self.nodes.extend(other.nodes);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn plain_text(&self) -> String {
+        let mut text = String::new();
+        self.append_to(&mut text, &mut vec![]);
+        text
+    }
+
+    pub fn to_markdown_v2(&self) -> String {
+        let mut out = String::new();
+        self.markdown_into(&mut out);
+        out
+    }
+
+    fn markdown_into(&self, out: &mut String) {
+        for node in &self.nodes {
+            match node {
+                Node::Text(text) => out.push_str(&escape_markdown(text)),
+                Node::Code(code) => {
+                    out.push('`');
+                    out.push_str(&escape_with(code, "\\`"));
+                    out.push('`');
+                }
+                Node::Pre { code, language } => {
+                    out.push_str("```");
+                    out.push_str(language.as_deref().unwrap_or_default());
+                    out.push('\n');
+                    out.push_str(&escape_with(code, "\\`"));
+                    out.push_str("\n```");
+                }
+                Node::Styled(Style::Quote, inner) => {
+                    if !out.is_empty() && !out.ends_with('\n') {
+                        out.push('\n');
+                    }
+
+                    for line in inner.to_markdown_v2().split('\n') {
+                        out.push('>');
+                        out.push_str(line);
+                        out.push('\n');
+                    }
+                }
+                Node::Styled(Style::Link(url), inner) => {
+                    out.push('[');
+                    inner.markdown_into(out);
+                    out.push_str("](");
+                    out.push_str(&escape_with(url, "\\)"));
+                    out.push(')');
+                }
+                Node::Styled(Style::Italic | Style::Underline, inner) => {
+                    let marker = match node {
+                        Node::Styled(Style::Italic, _) => "_",
+                        _ => "__",
+                    };
+
+                    push_underscores(out, marker);
+                    inner.markdown_into(out);
+                    push_underscores(out, marker);
+                }
+                Node::Styled(style, inner) => {
+                    let marker = match style {
+                        Style::Bold => "*",
+                        Style::Strikethrough => "~",
+                        _ => "||",
+                    };
+
+                    out.push_str(marker);
+                    inner.markdown_into(out);
+                    out.push_str(marker);
+                }
+            }
+        }
+    }
+
+    pub fn to_html(&self) -> String {
+        let mut out = String::new();
+
+        for node in &self.nodes {
+            match node {
+                Node::Text(text) => out.push_str(&escape_html(text)),
+                Node::Code(code) => out.push_str(&format!("<code>{}</code>", escape_html(code))),
+                Node::Pre {
+                    code,
+                    language: Some(language),
+                } => out.push_str(&format!(
+                    "<pre><code class=\"language-{}\">{}</code></pre>",
+                    escape_html(language),
+                    escape_html(code)
+                )),
+                Node::Pre { code, .. } => {
+                    out.push_str(&format!("<pre>{}</pre>", escape_html(code)))
+                }
+                Node::Styled(style, inner) => {
+                    let (open, close) = match style {
+                        Style::Bold => ("<b>".to_owned(), "</b>"),
+                        Style::Italic => ("<i>".to_owned(), "</i>"),
+                        Style::Underline => ("<u>".to_owned(), "</u>"),
+                        Style::Strikethrough => ("<s>".to_owned(), "</s>"),
+                        Style::Spoiler => ("<tg-spoiler>".to_owned(), "</tg-spoiler>"),
+                        Style::Quote => ("<blockquote>".to_owned(), "</blockquote>"),
+                        Style::Link(url) => (format!("<a href=\"{}\">", escape_html(url)), "</a>"),
+                    };
+
+                    out.push_str(&open);
+                    out.push_str(&inner.to_html());
+                    out.push_str(close);
+                }
+            }
+        }
+
+        out
+    }
+
+    // plain text plus entities, for sending without a parse mode
+    pub fn to_entities(&self) -> (String, Vec<MessageEntity>) {
+        let mut text = String::new();
+        let mut entities = vec![];
+        self.append_to(&mut text, &mut entities);
+        (text, entities)
+    }
+
+    // for adding to a message that's already formatted by entities
+    pub fn append_to(&self, text: &mut String, entities: &mut Vec<MessageEntity>) {
+        let mut units = text.encode_utf16().count();
+        self.entities_into(text, &mut units, entities);
+    }
+
+    fn entities_into(
+        &self,
+        text: &mut String,
+        units: &mut usize,
+        entities: &mut Vec<MessageEntity>,
+    ) {
+        for node in &self.nodes {
+            let start = *units;
+            // parents go before their children, so the entities stay sorted by offset
+            let index = entities.len();
+
+            let kind = match node {
+                Node::Text(s) => {
+                    push_text(text, units, s);
+                    None
+                }
+                Node::Code(code) => {
+                    push_text(text, units, code);
+                    Some(MessageEntityKind::Code)
+                }
+                Node::Pre { code, language } => {
+                    push_text(text, units, code);
+                    Some(MessageEntityKind::Pre {
+                        language: language.clone(),
+                    })
+                }
+                Node::Styled(style, inner) => {
+                    inner.entities_into(text, units, entities);
+
+                    match style {
+                        Style::Bold => Some(MessageEntityKind::Bold),
+                        Style::Italic => Some(MessageEntityKind::Italic),
+                        Style::Underline => Some(MessageEntityKind::Underline),
+                        Style::Strikethrough => Some(MessageEntityKind::Strikethrough),
+                        Style::Spoiler => Some(MessageEntityKind::Spoiler),
+                        // no blockquote entity in this version of the Bot API types
+                        Style::Quote => None,
+                        Style::Link(url) => url
+                            .parse()
+                            .ok()
+                            .map(|url| MessageEntityKind::TextLink { url }),
+                    }
+                }
+            };
+
+            if let (Some(kind), true) = (kind, *units > start) {
+                entities.insert(
+                    index,
+                    MessageEntity {
+                        kind,
+                        offset: start,
+                        length: *units - start,
+                    },
+                );
+            }
+        }
+    }
+
+    // text for a message with `parse_mode`, and its entities when there's none
+    pub fn render(&self, parse_mode: Option<ParseMode>) -> (String, Option<Vec<MessageEntity>>) {
+        match parse_mode {
+            Some(ParseMode::MarkdownV2) => (self.to_markdown_v2(), None),
+            Some(ParseMode::Html) => (self.to_html(), None),
+            _ => {
+                let (text, entities) = self.to_entities();
+                (text, Some(entities))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sample() -> RichText {
+        RichText::new()
+            .text("1+1=2 ")
+            .bold(RichText::from("bold ").italic(RichText::new().underline("both")))
+            .text(" 🙂 ")
+            .link("a <link>", "https://example.com/?a=(1)&b=2")
+            .code("x`y")
+            .pre("fn main() {}", Some("rust"))
+    }
+
+    #[test]
+    fn renders_every_format() {
+        assert_eq!(
+            sample().to_markdown_v2(),
+            "1\\+1\\=2 *bold _\r__both__\r_* 🙂 [a <link\\>](https://example.com/?a=(1\\)&b=2)\
+             `x\\`y````rust\nfn main() {}\n```"
+        );
+
+        assert_eq!(
+            sample().to_html(),
+            "1+1=2 <b>bold <i><u>both</u></i></b> 🙂 \
+             <a href=\"https://example.com/?a=(1)&amp;b=2\">a &lt;link&gt;</a>\
+             <code>x`y</code><pre><code class=\"language-rust\">fn main() {}</code></pre>"
+        );
+
+        let (text, entities) = sample().to_entities();
+        assert_eq!(text, "1+1=2 bold both 🙂 a <link>x`yfn main() {}");
+
+        let spans: Vec<_> = entities
+            .iter()
+            .map(|e| {
+                (
+                    e.offset,
+                    e.length,
+                    text.encode_utf16().skip(e.offset).take(e.length),
+                )
+            })
+            .map(|(offset, length, span)| {
+                (
+                    offset,
+                    length,
+                    String::from_utf16_lossy(&span.collect::<Vec<_>>()),
+                )
+            })
+            .collect();
+        assert_eq!(
+            spans,
+            [
+                (6, 9, "bold both".to_owned()),
+                (11, 4, "both".to_owned()),
+                (11, 4, "both".to_owned()),
+                // the emoji is two code units
+                (19, 8, "a <link>".to_owned()),
+                (27, 3, "x`y".to_owned()),
+                (30, 12, "fn main() {}".to_owned()),
+            ]
+        );
+        assert_eq!(entities[1].kind, MessageEntityKind::Italic);
+        assert_eq!(entities[2].kind, MessageEntityKind::Underline);
+
+        assert_eq!(
+            RichText::from("say ").quote("hi\nthere").to_markdown_v2(),
+            "say \n>hi\n>there\n"
+        );
+    }
+}
@@ -0,0 +1,417 @@
+use teloxide::types::{MessageEntity, ParseMode};
+
+// Telegram's limit, in UTF-16 code units; markup is counted too, so the parts always fit
+pub const MAX_MESSAGE_LEN: usize = 4096;
+
+fn utf16_len(text: &str) -> usize {
+    text.encode_utf16().count()
+}
+
+// an entity open at a split point: closed at the end of one part and reopened in the next
+#[derive(Debug, Clone, PartialEq)]
+struct Marker {
+    open: String,
+    close: String,
+}
+
+impl Marker {
+    fn new(open: &str, close: &str) -> Self {
+        Self {
+            open: open.to_owned(),
+            close: close.to_owned(),
+        }
+    }
+}
+
+struct Point {
+    at: usize,
+    // where the next part starts, past the newline or space split on
+    resume: usize,
+    // 3 paragraph, 2 line, 1 word, 0 anywhere
+    rank: u8,
+    units: usize,
+    open: Vec<Marker>,
+}
+
+#[derive(Default)]
+struct Scan {
+    points: Vec<Point>,
+    counted: usize,
+    units: usize,
+    after_opening: bool,
+}
+
+impl Scan {
+    fn point(&mut self, text: &str, at: usize, open: &[Marker]) {
+        // a part ending right after an opening marker would end in an empty entity
+        if std::mem::take(&mut self.after_opening) {
+            return;
+        }
+
+        self.units += utf16_len(&text[self.counted..at]);
+        self.counted = at;
+
+        let rest = &text[at..];
+        let (rank, skip) = if rest.starts_with("\n\n") {
+            (3, 2)
+        } else if rest.starts_with('\n') {
+            (2, 1)
+        } else if rest.starts_with(' ') {
+            (1, 1)
+        } else {
+            (0, 0)
+        };
+
+        self.points.push(Point {
+            at,
+            resume: at + skip,
+            rank,
+            units: self.units,
+            open: open.to_vec(),
+        });
+    }
+
+    fn opened(&mut self) {
+        self.after_opening = true;
+    }
+
+    // same for a part starting right at a closing marker
+    fn closing_at(&mut self, at: usize) {
+        while self.points.last().is_some_and(|point| point.resume == at) {
+            self.points.pop();
+        }
+    }
+}
+
+fn plain_points(text: &str) -> Vec<Point> {
+    let mut scan = Scan::default();
+
+    for (at, _) in text.char_indices() {
+        scan.point(text, at, &[]);
+    }
+
+    scan.point(text, text.len(), &[]);
+    scan.points
+}
+
+fn markdown_points(text: &str) -> Vec<Point> {
+    let mut scan = Scan::default();
+    let mut open: Vec<Marker> = vec![];
+    // a link's url isn't known until its end, so links are never split
+    let mut links = 0;
+    let mut in_url = false;
+    let mut i = 0;
+
+    while i < text.len() {
+        if links == 0 {
+            scan.point(text, i, &open);
+        }
+
+        let rest = &text[i..];
+        let c = rest.chars().next().unwrap_or_default();
+
+        if c == '\\' {
+            i += 1 + rest[1..].chars().next().map_or(0, char::len_utf8);
+            continue;
+        }
+
+        if in_url {
+            if c == ')' {
+                in_url = false;
+                links -= 1;
+            }
+            i += c.len_utf8();
+            continue;
+        }
+
+        // only the closing backticks mean anything inside code
+        if let Some(code) = open.last().filter(|marker| marker.close.starts_with('`')) {
+            if rest.starts_with(&code.close) {
+                scan.closing_at(i);
+                i += code.close.len();
+                open.pop();
+            } else {
+                i += c.len_utf8();
+            }
+            continue;
+        }
+
+        let marker = match c {
+            '`' if rest.starts_with("```") => {
+                let line = rest.find('\n').map_or(rest.len(), |end| end + 1);
+                Marker::new(&rest[..line], "```")
+            }
+            '`' => Marker::new("`", "`"),
+            '_' if rest.starts_with("__") => Marker::new("__", "__"),
+            '|' if rest.starts_with("||") => Marker::new("||", "||"),
+            '_' | '*' | '~' => Marker::new(&rest[..1], &rest[..1]),
+            '[' => {
+                links += 1;
+                i += 1;
+                continue;
+            }
+            ']' if links > 0 && rest.starts_with("](") => {
+                in_url = true;
+                i += 2;
+                continue;
+            }
+            _ => {
+                i += c.len_utf8();
+                continue;
+            }
+        };
+
+        if open.last() == Some(&marker) {
+            scan.closing_at(i);
+            i += marker.open.len();
+            open.pop();
+        } else {
+            scan.opened();
+            i += marker.open.len();
+            open.push(marker);
+        }
+    }
+
+    scan.after_opening = false;
+    scan.point(text, text.len(), &open);
+    scan.points
+}
+
+fn html_points(text: &str) -> Vec<Point> {
+    let mut scan = Scan::default();
+    let mut open: Vec<Marker> = vec![];
+    let mut i = 0;
+
+    while i < text.len() {
+        scan.point(text, i, &open);
+
+        let rest = &text[i..];
+        let c = rest.chars().next().unwrap_or_default();
+
+        match (c, rest.find('>'), rest.find(';')) {
+            ('<', Some(end), _) if rest.starts_with("</") => {
+                scan.closing_at(i);
+                open.pop();
+                i += end + 1;
+            }
+            ('<', Some(end), _) => {
+                let tag = &rest[..=end];
+                let name = tag[1..tag.len() - 1]
+                    .split_whitespace()
+                    .next()
+                    .unwrap_or_default();
+                scan.opened();
+                open.push(Marker::new(tag, &format!("</{}>", name)));
+                i += end + 1;
+            }
+            ('&', _, Some(end)) => i += end + 1,
+            _ => i += c.len_utf8(),
+        }
+    }
+
+    scan.after_opening = false;
+    scan.point(text, text.len(), &open);
+    scan.points
+}
+
+struct Part<'a> {
+    start: usize,
+    end: usize,
+    start_units: usize,
+    end_units: usize,
+    reopen: &'a [Marker],
+    close: &'a [Marker],
+}
+
+fn choose_parts<'a>(text: &str, points: &'a [Point], max: usize) -> Vec<Part<'a>> {
+    let mut parts = vec![];
+    let (mut start, mut start_units, mut reopen): (usize, usize, &[Marker]) = (0, 0, &[]);
+
+    loop {
+        let opening_units: usize = reopen.iter().map(|m| utf16_len(&m.open)).sum();
+
+        let mut fitting: Option<&Point> = None;
+        let mut best: Option<&Point> = None;
+
+        for point in points.iter().filter(|point| point.at > start) {
+            let body = point.units - start_units;
+            let closing: usize = point.open.iter().map(|m| utf16_len(&m.close)).sum();
+
+            if opening_units + body > max {
+                break;
+            }
+
+            if opening_units + body + closing > max {
+                continue;
+            }
+
+            fitting = Some(point);
+
+            if point.at == text.len()
+                || body >= max / 2 && best.is_none_or(|best| point.rank >= best.rank)
+            {
+                best = Some(point);
+            }
+        }
+
+        // nothing fits, e.g. a huge link: an oversized part beats dropping text
+        let Some(point) = best
+            .or(fitting)
+            .or_else(|| points.iter().find(|point| point.at > start))
+        else {
+            break;
+        };
+
+        parts.push(Part {
+            start,
+            end: point.at,
+            start_units,
+            end_units: point.units,
+            reopen,
+            close: &point.open,
+        });
+
+        if point.at == text.len() {
+            break;
+        }
+
+        start_units = point.units + (point.resume - point.at);
+        start = point.resume;
+        reopen = &point.open;
+    }
+
+    parts
+}
+
+// splits at paragraphs, then lines, then words; entities open at a split are closed and reopened
+pub fn split_text(text: &str, parse_mode: Option<ParseMode>, max: usize) -> Vec<String> {
+    if utf16_len(text) <= max {
+        return vec![text.to_owned()];
+    }
+
+    #[allow(deprecated)]
+    let points = match parse_mode {
+        Some(ParseMode::MarkdownV2) => markdown_points(text),
+        Some(ParseMode::Html) => html_points(text),
+        Some(ParseMode::Markdown) | None => plain_points(text),
+    };
+
+    choose_parts(text, &points, max)
+        .into_iter()
+        .map(|part| {
+            let opening: String = part.reopen.iter().map(|m| m.open.as_str()).collect();
+            let closing: String = part.close.iter().rev().map(|m| m.close.as_str()).collect();
+
+            format!("{}{}{}", opening, &text[part.start..part.end], closing)
+        })
+        .filter(|part| !part.trim().is_empty())
+        .collect()
+}
+
+// the same for text formatted by `entities`: each part gets the pieces of them that fall into it
+pub fn split_entities(
+    text: &str,
+    entities: &[MessageEntity],
+    max: usize,
+) -> Vec<(String, Vec<MessageEntity>)> {
+    if utf16_len(text) <= max {
+        return vec![(text.to_owned(), entities.to_vec())];
+    }
+
+    let points = plain_points(text);
+
+    choose_parts(text, &points, max)
+        .into_iter()
+        .map(|part| {
+            let entities = entities
+                .iter()
+                .filter_map(|entity| {
+                    let start = entity.offset.max(part.start_units);
+                    let end = (entity.offset + entity.length).min(part.end_units);
+
+                    (end > start).then(|| MessageEntity {
+                        kind: entity.kind.clone(),
+                        offset: start - part.start_units,
+                        length: end - start,
+                    })
+                })
+                .collect();
+
+            (text[part.start..part.end].to_owned(), entities)
+        })
+        .filter(|(part, _)| !part.trim().is_empty())
+        .collect()
+}
+
+pub fn split_message(
+    text: &str,
+    parse_mode: Option<ParseMode>,
+    entities: Option<&[MessageEntity]>,
+    max: usize,
+) -> Vec<(String, Option<Vec<MessageEntity>>)> {
+    match entities {
+        Some(entities) => split_entities(text, entities, max)
+            .into_iter()
+            .map(|(part, entities)| (part, Some(entities)))
+            .collect(),
+        None => split_text(text, parse_mode, max)
+            .into_iter()
+            .map(|part| (part, None))
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn splits_at_boundaries_and_keeps_entities() {
+        let text = format!(
+            "{}\n\n{}\n{}",
+            "a".repeat(30),
+            "b".repeat(20),
+            "c ".repeat(10)
+        );
+        assert_eq!(
+            split_text(&text, None, 40),
+            vec!["a".repeat(30), "b".repeat(20), "c ".repeat(10),]
+        );
+
+        let text = format!("*bold {}* plain", "x ".repeat(12));
+        for part in split_text(&text, Some(ParseMode::MarkdownV2), 16) {
+            assert!(part.chars().count() <= 16);
+            assert_eq!(part.matches('*').count() % 2, 0, "{}", part);
+        }
+
+        let text = format!("```rust\n{}```", "let x = 1;\n".repeat(4));
+        let parts = split_text(&text, Some(ParseMode::MarkdownV2), 40);
+        assert_eq!(parts[0], "```rust\nlet x = 1;\nlet x = 1;```");
+        assert_eq!(parts[1], "```rust\nlet x = 1;\nlet x = 1;\n```");
+
+        let text = format!("intro [a link](http://x.y) \\*{}", "z".repeat(10));
+        let parts = split_text(&text, Some(ParseMode::MarkdownV2), 30);
+        assert_eq!(parts[0], "intro [a link](http://x.y)");
+
+        let text = format!("<b>{} &amp; {}</b>", "d".repeat(10), "e".repeat(10));
+        let parts = split_text(&text, Some(ParseMode::Html), 24);
+        assert_eq!(parts, vec!["<b>dddddddddd &amp;</b>", "<b>eeeeeeeeee</b>"]);
+
+        // code units, not chars
+        assert_eq!(split_text(&"🙂".repeat(3), None, 4).len(), 2);
+
+        let bold = |offset, length| MessageEntity {
+            kind: teloxide::types::MessageEntityKind::Bold,
+            offset,
+            length,
+        };
+        let parts = split_entities("🙂 aaaa bbbb", &[bold(3, 9)], 8);
+        assert_eq!(
+            parts,
+            vec![
+                ("🙂 aaaa".to_owned(), vec![bold(3, 4)]),
+                ("bbbb".to_owned(), vec![bold(0, 4)]),
+            ]
+        );
+    }
+}
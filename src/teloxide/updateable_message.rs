@@ -1,19 +1,45 @@
+use std::{iter, sync::Arc};
+
+use serde::{Deserialize, Serialize};
 use teloxide::{
-    payloads::{self, EditMessageReplyMarkupSetters, SendMessageSetters},
-    requests::{JsonRequest, Requester},
-    types::{ChatId, InlineKeyboardMarkup, MessageId, Recipient, ReplyMarkup},
-    Bot,
+    payloads::{self, EditMessageReplyMarkupSetters, EditMessageTextSetters, SendMessageSetters},
+    requests::{HasPayload, JsonRequest, Output, Request, Requester},
+    types::{ChatId, InlineKeyboardMarkup, MessageId, ParseMode, Recipient, ReplyMarkup},
+    ApiError, Bot, RequestError,
+};
+
+use super::{
+    edit_scheduler::EditScheduler,
+    progress_message::{is_gone, with_retries},
+    rich_text::RichText,
+    split::{split_text, MAX_MESSAGE_LEN},
 };
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UpdateableMessage {
     pub chat_id: ChatId,
     pub message_id: MessageId,
     pub text: String,
     pub reply_markup: Option<InlineKeyboardMarkup>,
+    // text past the length limit spills into these, in order; the keyboard goes on the last one
+    #[serde(default)]
+    pub continuations: Vec<MessageId>,
+    #[serde(default)]
+    pub parse_mode: Option<ParseMode>,
+    // the global one if not set
+    #[serde(skip)]
+    pub scheduler: Option<Arc<EditScheduler>>,
 }
 
 impl UpdateableMessage {
     pub async fn new(req: JsonRequest<payloads::SendMessage>) -> anyhow::Result<Self> {
+        Self::new_with_scheduler(req, EditScheduler::global()).await
+    }
+
+    pub async fn new_with_scheduler(
+        req: JsonRequest<payloads::SendMessage>,
+        scheduler: Arc<EditScheduler>,
+    ) -> anyhow::Result<Self> {
         let Recipient::Id(chat_id) = req.chat_id else {
             return Err(anyhow::anyhow!("chat_id is not a ChatId"));
         };
@@ -28,42 +54,197 @@ impl UpdateableMessage {
             }
         };
 
-        Ok(Self {
+        let parts = split_text(&req.text, req.parse_mode, MAX_MESSAGE_LEN);
+        let mut ids = vec![];
+
+        for (i, part) in parts.iter().enumerate() {
+            let mut req = req.clone();
+            req.payload_mut().text = part.clone();
+
+            if i + 1 < parts.len() {
+                req.payload_mut().reply_markup = None;
+            }
+
+            let message = with_retries(&scheduler, &chat_id.into(), || req.send_ref()).await?;
+            ids.push(message.id);
+        }
+
+        let mut message = Self {
             chat_id,
+            message_id: MessageId(0),
             text: req.text.to_owned(),
             reply_markup: reply_markup.cloned(),
-            message_id: req.await?.id,
-        })
-    }
+            continuations: vec![],
+            parse_mode: req.parse_mode,
+            scheduler: Some(scheduler),
+        };
+        message.set_ids(ids)?;
 
-    pub async fn resend(&mut self, bot: &Bot) -> anyhow::Result<()> {
-        let _ = bot.delete_message(self.chat_id, self.message_id);
+        Ok(message)
+    }
 
-        let mut crt = bot.send_message(self.chat_id, &self.text);
+    // MarkdownV2, so `update_rich` can keep the formatting
+    pub async fn send_rich(
+        bot: &Bot,
+        chat_id: ChatId,
+        text: &RichText,
+        reply_markup: Option<InlineKeyboardMarkup>,
+    ) -> anyhow::Result<Self> {
+        let mut req = bot
+            .send_message(chat_id, text.to_markdown_v2())
+            .parse_mode(ParseMode::MarkdownV2);
 
-        if let Some(reply_markup) = self.reply_markup.to_owned() {
-            crt = crt.reply_markup(reply_markup);
+        if let Some(reply_markup) = reply_markup {
+            req = req.reply_markup(reply_markup);
         }
 
-        self.message_id = crt.await?.id;
+        Self::new(req).await
+    }
+
+    pub fn message_ids(&self) -> Vec<MessageId> {
+        iter::once(self.message_id)
+            .chain(self.continuations.iter().copied())
+            .collect()
+    }
+
+    fn set_ids(&mut self, ids: Vec<MessageId>) -> anyhow::Result<()> {
+        let Some((&first, rest)) = ids.split_first() else {
+            return Err(anyhow::anyhow!("message has no text to send"));
+        };
+
+        self.message_id = first;
+        self.continuations = rest.to_vec();
 
         Ok(())
     }
 
-    pub async fn update_text(&mut self, bot: &Bot, text: &str) -> anyhow::Result<()> {
-        if self.text != text {
-            self.text = text.to_owned();
+    pub fn with_scheduler(mut self, scheduler: Arc<EditScheduler>) -> Self {
+        self.scheduler = Some(scheduler);
+        self
+    }
+
+    fn scheduler(&self) -> Arc<EditScheduler> {
+        self.scheduler.clone().unwrap_or_else(EditScheduler::global)
+    }
+
+    // every request goes through the shared budget, see `EditScheduler`
+    async fn budget(&self) {
+        self.scheduler().acquire(&self.chat_id.into()).await
+    }
+
+    // the same, waiting out RetryAfter and network hiccups
+    async fn send<R>(&self, req: &R) -> Result<Output<R>, RequestError>
+    where
+        R: Request<Err = RequestError>,
+    {
+        with_retries(&self.scheduler(), &self.chat_id.into(), || req.send_ref()).await
+    }
+
+    async fn edit<R>(&self, req: &R) -> Result<(), RequestError>
+    where
+        R: Request<Err = RequestError>,
+    {
+        match self.send(req).await {
+            Ok(_) | Err(RequestError::Api(ApiError::MessageNotModified)) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn parts(&self) -> Vec<String> {
+        split_text(&self.text, self.parse_mode, MAX_MESSAGE_LEN)
+    }
+
+    async fn send_part(&self, bot: &Bot, text: &str, last: bool) -> anyhow::Result<MessageId> {
+        let mut req = bot.send_message(self.chat_id, text);
+
+        if let Some(parse_mode) = self.parse_mode {
+            req = req.parse_mode(parse_mode);
+        }
+
+        if let (true, Some(reply_markup)) = (last, self.reply_markup.to_owned()) {
+            req = req.reply_markup(reply_markup);
+        }
+
+        Ok(self.send(&req).await?.id)
+    }
+
+    pub async fn resend(&mut self, bot: &Bot) -> anyhow::Result<()> {
+        for message_id in self.message_ids() {
+            self.budget().await;
+            let _ = bot.delete_message(self.chat_id, message_id).await;
+        }
+
+        let parts = self.parts();
+        let mut ids = vec![];
+
+        for (i, part) in parts.iter().enumerate() {
+            ids.push(self.send_part(bot, part, i + 1 == parts.len()).await?);
+        }
+
+        self.set_ids(ids)
+    }
+
+    // edits only the parts that changed, sends the ones that are new and deletes the ones left over
+    async fn sync(
+        &mut self,
+        bot: &Bot,
+        old_parts: &[String],
+        old_markup: Option<InlineKeyboardMarkup>,
+    ) -> anyhow::Result<()> {
+        let old_ids = self.message_ids();
+        let parts = self.parts();
+        let mut ids = vec![];
+
+        for (i, part) in parts.iter().enumerate() {
+            let last = i + 1 == parts.len();
+
+            let Some(&message_id) = old_ids.get(i) else {
+                let message_id = self.send_part(bot, part, last).await?;
+                // so a failure further on doesn't leave it behind
+                self.continuations.push(message_id);
+                ids.push(message_id);
+                continue;
+            };
+
+            let reply_markup = self.reply_markup.clone().filter(|_| last);
+            let old_reply_markup = old_markup.clone().filter(|_| i + 1 == old_parts.len());
+
+            if old_parts.get(i) != Some(part) {
+                let mut req = bot.edit_message_text(self.chat_id, message_id, part);
+
+                if let Some(parse_mode) = self.parse_mode {
+                    req = req.parse_mode(parse_mode);
+                }
+
+                if let Some(reply_markup) = reply_markup {
+                    req = req.reply_markup(reply_markup);
+                }
 
-            let res = bot
-                .edit_message_text(self.chat_id, self.message_id, text)
-                .await;
+                self.edit(&req).await?;
+            } else if old_reply_markup != reply_markup {
+                let mut req = bot.edit_message_reply_markup(self.chat_id, message_id);
 
-            if let Err(_) = res {
-                self.resend(bot).await?;
+                if let Some(reply_markup) = reply_markup {
+                    req = req.reply_markup(reply_markup);
+                }
+
+                self.edit(&req).await?;
             }
+
+            ids.push(message_id);
         }
 
-        Ok(())
+        for &message_id in old_ids.iter().skip(parts.len()) {
+            self.budget().await;
+            let _ = bot.delete_message(self.chat_id, message_id).await;
+        }
+
+        self.set_ids(ids)
+    }
+
+    pub async fn update_text(&mut self, bot: &Bot, text: &str) -> anyhow::Result<()> {
+        let reply_markup = self.reply_markup.clone();
+        self.update(bot, text, reply_markup).await
     }
 
     pub async fn update_markup(
@@ -71,27 +252,202 @@ impl UpdateableMessage {
         bot: &Bot,
         reply_markup: Option<InlineKeyboardMarkup>,
     ) -> anyhow::Result<()> {
-        if self.reply_markup != reply_markup {
-            let mut req = bot.edit_message_reply_markup(self.chat_id, self.message_id);
+        let text = self.text.clone();
+        self.update(bot, &text, reply_markup).await
+    }
 
-            if let Some(reply_markup) = &reply_markup {
-                req = req.reply_markup(reply_markup.clone());
-            }
+    // text and keyboard in one edit; editing only the text would drop the keyboard
+    pub async fn update(
+        &mut self,
+        bot: &Bot,
+        text: &str,
+        reply_markup: Option<InlineKeyboardMarkup>,
+    ) -> anyhow::Result<()> {
+        self.update_with(bot, text, reply_markup, self.parse_mode)
+            .await
+    }
 
-            let res = req.await;
+    async fn update_with(
+        &mut self,
+        bot: &Bot,
+        text: &str,
+        reply_markup: Option<InlineKeyboardMarkup>,
+        parse_mode: Option<ParseMode>,
+    ) -> anyhow::Result<()> {
+        if self.text == text && self.reply_markup == reply_markup && self.parse_mode == parse_mode {
+            return Ok(());
+        }
 
-            if let Err(_) = res {
-                self.resend(bot).await?;
-            }
+        // split the way they were sent; in another mode even the same text needs an edit
+        let old_parts = match self.parse_mode == parse_mode {
+            true => self.parts(),
+            false => vec![],
+        };
+        let old_text = std::mem::replace(&mut self.text, text.to_owned());
+        let old_markup = std::mem::replace(&mut self.reply_markup, reply_markup);
+        let old_parse_mode = std::mem::replace(&mut self.parse_mode, parse_mode);
+
+        let Err(e) = self.sync(bot, &old_parts, old_markup.clone()).await else {
+            return Ok(());
+        };
 
-            self.reply_markup = reply_markup;
+        // only a part that's gone is worth starting over for; deleting the rest on anything
+        // else, and then failing to send, would leave the chat with nothing
+        if e.downcast_ref::<RequestError>().is_some_and(is_gone) {
+            return self.resend(bot).await;
         }
 
-        Ok(())
+        // so the next update tries again instead of thinking it's already shown
+        self.text = old_text;
+        self.reply_markup = old_markup;
+        self.parse_mode = old_parse_mode;
+
+        Err(e)
+    }
+
+    // HTML if that's what the message was sent with, MarkdownV2 otherwise
+    pub async fn update_rich(
+        &mut self,
+        bot: &Bot,
+        text: &RichText,
+        reply_markup: Option<InlineKeyboardMarkup>,
+    ) -> anyhow::Result<()> {
+        let (text, parse_mode) = match self.parse_mode {
+            Some(ParseMode::Html) => (text.to_html(), ParseMode::Html),
+            _ => (text.to_markdown_v2(), ParseMode::MarkdownV2),
+        };
+
+        self.update_with(bot, &text, reply_markup, Some(parse_mode))
+            .await
     }
 
     pub async fn delete(self, bot: &Bot) -> anyhow::Result<()> {
-        bot.delete_message(self.chat_id, self.message_id).await?;
+        for message_id in self.message_ids() {
+            self.budget().await;
+            bot.delete_message(self.chat_id, message_id).await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::atomic::{AtomicBool, AtomicI32, Ordering},
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::teloxide::{
+        edit_scheduler::{EditLimits, Rate},
+        fake_api,
+    };
+
+    #[tokio::test]
+    async fn resends_only_when_gone() -> anyhow::Result<()> {
+        let scheduler = Arc::new(EditScheduler::new(EditLimits {
+            per_chat: Rate::new(100, Duration::from_secs(1)),
+            ..Default::default()
+        }));
+
+        let next_id = AtomicI32::new(1);
+        let throttled = AtomicBool::new(false);
+
+        let (bot, requests) = fake_api::serve(move |method, payload| {
+            match (method, payload["text"].as_str().unwrap_or_default()) {
+                ("SendMessage", text) => {
+                    fake_api::message(next_id.fetch_add(1, Ordering::SeqCst), text)
+                }
+                ("DeleteMessage", _) => fake_api::ok(),
+                (_, "throttled") if !throttled.swap(true, Ordering::SeqCst) => {
+                    fake_api::retry_after(1)
+                }
+                (_, "same") => fake_api::error(
+                    400,
+                    "Bad Request: message is not modified: specified new message content and \
+                     reply markup are exactly the same as a current content and reply markup \
+                     of the message",
+                ),
+                (_, "gone") => fake_api::error(400, "Bad Request: message to edit not found"),
+                (_, "bad") => fake_api::error(400, "Bad Request: can't parse entities"),
+                (_, text) => fake_api::message(1, text),
+            }
+        })
+        .await?;
+
+        let mut message =
+            UpdateableMessage::new_with_scheduler(bot.send_message(ChatId(1), "first"), scheduler)
+                .await?;
+
+        message.update_text(&bot, "throttled").await?;
+        message.update_text(&bot, "same").await?;
+        assert_eq!(message.message_ids(), vec![MessageId(1)]);
+
+        message.update_text(&bot, "bad").await.unwrap_err();
+        assert_eq!(message.text, "same");
+        assert_eq!(message.message_ids(), vec![MessageId(1)]);
+
+        message.update_text(&bot, "gone").await?;
+        assert_eq!(message.message_ids(), vec![MessageId(2)]);
+
+        let methods: Vec<_> = requests
+            .lock()
+            .iter()
+            .map(|(method, _)| method.clone())
+            .collect();
+        assert_eq!(
+            methods,
+            [
+                "SendMessage",
+                "EditMessageText",
+                "EditMessageText",
+                "EditMessageText",
+                "EditMessageText",
+                "EditMessageText",
+                "DeleteMessage",
+                "SendMessage"
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn switches_mode_only_once_edited() -> anyhow::Result<()> {
+        let (bot, requests) = fake_api::serve(|method, payload| {
+            match (method, payload["text"].as_str().unwrap_or_default()) {
+                ("EditMessageText", "bad") => {
+                    fake_api::error(400, "Bad Request: can't parse entities")
+                }
+                (_, text) => fake_api::message(1, text),
+            }
+        })
+        .await?;
+
+        let mut message = UpdateableMessage::new_with_scheduler(
+            bot.send_message(ChatId(5), "first"),
+            Arc::new(EditScheduler::default()),
+        )
+        .await?;
+
+        message
+            .update_rich(&bot, &RichText::from("bad"), None)
+            .await
+            .unwrap_err();
+        assert_eq!(message.parse_mode, None);
+        assert_eq!(message.text, "first");
+
+        message
+            .update_rich(&bot, &RichText::new().bold("good"), None)
+            .await?;
+        assert_eq!(message.parse_mode, Some(ParseMode::MarkdownV2));
+        assert_eq!(message.text, "*good*");
+
+        let requests = requests.lock();
+        let (method, payload) = requests.last().unwrap();
+        assert_eq!(method, "EditMessageText");
+        assert_eq!(payload["parse_mode"], "MarkdownV2");
 
         Ok(())
     }